@@ -0,0 +1,120 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+
+use futures::sync::mpsc;
+
+use super::worker::NonceStrategy;
+
+/// Settings of a running miner that can be changed without restarting it.
+pub struct Control {
+    paused: AtomicBool,
+    pause_lock: Mutex<()>,
+    resumed: Condvar,
+    jobs: AtomicUsize,
+    threads: AtomicUsize,
+    nonce_strategy: Mutex<NonceStrategy>,
+    reconnect: Mutex<Vec<mpsc::UnboundedSender<()>>>,
+}
+
+impl Control {
+    pub fn new(jobs: usize, threads: usize, nonce_strategy: NonceStrategy) -> Self {
+        Self {
+            paused: AtomicBool::new(false),
+            pause_lock: Mutex::new(()),
+            resumed: Condvar::new(),
+            jobs: AtomicUsize::new(jobs),
+            threads: AtomicUsize::new(threads),
+            nonce_strategy: Mutex::new(nonce_strategy),
+            reconnect: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn pause(&self) {
+        let _guard = self.pause_lock.lock().unwrap();
+        self.paused.store(true, Ordering::SeqCst);
+        info!("Mining is paused");
+    }
+
+    pub fn resume(&self) {
+        let _guard = self.pause_lock.lock().unwrap();
+        self.paused.store(false, Ordering::SeqCst);
+        self.resumed.notify_all();
+        info!("Mining is resumed");
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Blocks the calling mining thread while mining is paused.
+    pub fn wait_while_paused(&self) {
+        if !self.is_paused() {
+            return
+        }
+        let mut guard = self.pause_lock.lock().unwrap();
+        while self.is_paused() {
+            guard = self.resumed.wait(guard).unwrap();
+        }
+    }
+
+    pub fn jobs(&self) -> usize {
+        self.jobs.load(Ordering::SeqCst)
+    }
+
+    pub fn set_jobs(&self, jobs: usize) {
+        self.jobs.store(jobs, Ordering::SeqCst);
+        info!("The maximum number of parallel jobs is changed to {}", jobs);
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads.load(Ordering::SeqCst)
+    }
+
+    /// Changes the number of threads used by the jobs started after this call.
+    pub fn set_threads(&self, threads: usize) {
+        self.threads.store(threads, Ordering::SeqCst);
+        info!("The number of threads per job is changed to {}", threads);
+    }
+
+    pub fn nonce_strategy(&self) -> NonceStrategy {
+        *self.nonce_strategy.lock().unwrap()
+    }
+
+    /// Changes the nonce strategy of the jobs started after this call.
+    pub fn set_nonce_strategy(&self, nonce_strategy: NonceStrategy) {
+        *self.nonce_strategy.lock().unwrap() = nonce_strategy;
+        info!("The nonce strategy is changed to {:?}", nonce_strategy);
+    }
+
+    /// Asks the current connections to reconnect. Returns false if there is no connection to reconnect.
+    pub fn reconnect(&self) -> bool {
+        let mut senders = self.reconnect.lock().unwrap();
+        senders.retain(|tx| tx.unbounded_send(()).is_ok());
+        !senders.is_empty()
+    }
+
+    /// Returns a receiver that yields whenever `reconnect` is called, until it is dropped.
+    pub fn on_reconnect(&self) -> mpsc::UnboundedReceiver<()> {
+        let (tx, rx) = mpsc::unbounded();
+        let mut senders = self.reconnect.lock().unwrap();
+        senders.retain(|tx| !tx.is_closed());
+        senders.push(tx);
+        rx
+    }
+}
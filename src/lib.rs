@@ -21,38 +21,61 @@ extern crate futures;
 extern crate hyper;
 #[macro_use]
 extern crate log;
+extern crate native_tls;
+extern crate rand;
 extern crate rustc_hex;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
+extern crate sha2;
 extern crate tokio;
 extern crate tokio_executor;
+extern crate tokio_tls;
+extern crate tokio_tungstenite;
+extern crate url;
 
+mod control;
 mod rpc;
+/// The stratum client of the miner, for other tools that talk to a pool.
+pub mod stratum;
 mod worker;
 
 use std::sync::Arc;
 
-use rpc::{HttpRunner, RpcRunner, StratumRunner};
+use control::Control;
+use rpc::{HttpRunner, RpcRunner, StratumProxyRunner, StratumRunner, StratumServerRunner, WebSocketRunner};
 
-pub use rpc::{HttpConfig, RpcConfig, StratumConfig};
-pub use worker::Worker;
+pub use rpc::{
+    CredentialStore, Endpoint, HttpConfig, Listen, RpcConfig, StratumConfig, StratumIdentity, StratumPool,
+    StratumProxyConfig, StratumServerConfig, Strictness, SubmitPolicy, Verifier, WebSocketConfig,
+};
+pub use worker::{NonceStrategy, Worker};
 
 pub fn run<C: 'static + Config>(config: C) {
     let rpc_runner = match config.rpc_config() {
         RpcConfig::Http(config) => Box::new(HttpRunner::new(&config)) as Box<RpcRunner>,
         RpcConfig::Stratum(config) => Box::new(StratumRunner::new(&config)) as Box<RpcRunner>,
+        RpcConfig::StratumServer(config) => Box::new(StratumServerRunner::new(&config)) as Box<RpcRunner>,
+        RpcConfig::StratumProxy(config) => Box::new(StratumProxyRunner::new(&config)) as Box<RpcRunner>,
+        RpcConfig::WebSocket(config) => Box::new(WebSocketRunner::new(&config)) as Box<RpcRunner>,
     };
-    let jobs = config.jobs();
+    let control = Arc::new(Control::new(config.jobs(), config.threads(), config.nonce_strategy()));
     let recruiter = Arc::new(move || config.worker());
 
-    rpc_runner.run(recruiter, jobs);
+    rpc_runner.run(recruiter, control);
 }
 
 pub trait Config: Send + Sync {
     fn rpc_config(&self) -> RpcConfig;
     fn jobs(&self) -> usize;
+    /// The number of threads, each with its own worker, that search a job together.
+    fn threads(&self) -> usize {
+        1
+    }
+    fn nonce_strategy(&self) -> NonceStrategy {
+        NonceStrategy::Sequential
+    }
     fn worker(&self) -> Box<Worker>;
 }
@@ -0,0 +1,126 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::Arc;
+
+use futures::{Future, Stream};
+use hyper::service::service_fn;
+use hyper::{self, Body, Method, Request, Response, Server, StatusCode};
+use serde_json::{self, Value as JsonValue};
+
+use super::super::control::Control;
+use super::super::worker::NonceStrategy;
+
+type BoxFut = Box<Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+pub const PATH: &str = "/control";
+
+/// Serves the control API on 127.0.0.1:port.
+pub fn serve(port: u16, control: Arc<Control>) -> Box<Future<Item = (), Error = ()> + Send> {
+    let addr = ([127, 0, 0, 1], port).into();
+    info!("Control API listening on {:?}", addr);
+    Box::new(
+        Server::bind(&addr)
+            .serve(move || {
+                let control = Arc::clone(&control);
+                service_fn(move |req| handle(&control, req))
+            })
+            .map_err(|e| error!("control server error: {}", e)),
+    )
+}
+
+/// Handles a request to the control API.
+///
+/// `GET /control` returns the current settings. `POST /control/pause`, `/control/resume` and
+/// `/control/reconnect` take no body, while `POST /control/jobs`, `/control/threads` and
+/// `/control/nonce-strategy` take the new value as a JSON body.
+pub fn handle(control: &Arc<Control>, req: Request<Body>) -> BoxFut {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let control = Arc::clone(control);
+    Box::new(req.into_body().concat2().map(move |chunk| {
+        // The standalone control port sends every path here.
+        if !path.starts_with(PATH) {
+            return respond(StatusCode::NOT_FOUND, JsonValue::Null)
+        }
+        let value = serde_json::from_slice::<JsonValue>(&chunk).unwrap_or(JsonValue::Null);
+        match (method, &path[PATH.len()..]) {
+            (Method::GET, "") | (Method::GET, "/") => status(&control),
+            (Method::POST, "/pause") => {
+                control.pause();
+                status(&control)
+            }
+            (Method::POST, "/resume") => {
+                control.resume();
+                status(&control)
+            }
+            (Method::POST, "/reconnect") => {
+                if control.reconnect() {
+                    status(&control)
+                } else {
+                    respond(StatusCode::CONFLICT, json!({ "error": "There is no connection to reconnect" }))
+                }
+            }
+            (Method::POST, "/jobs") => match value.as_u64() {
+                Some(jobs) if jobs > 0 => {
+                    control.set_jobs(jobs as usize);
+                    status(&control)
+                }
+                _ => respond(StatusCode::BAD_REQUEST, json!({ "error": "jobs should be a positive integer" })),
+            },
+            (Method::POST, "/threads") => match value.as_u64() {
+                Some(threads) if threads > 0 => {
+                    control.set_threads(threads as usize);
+                    status(&control)
+                }
+                _ => respond(StatusCode::BAD_REQUEST, json!({ "error": "threads should be a positive integer" })),
+            },
+            (Method::POST, "/nonce-strategy") => match value.as_str().map(str::parse::<NonceStrategy>) {
+                Some(Ok(nonce_strategy)) => {
+                    control.set_nonce_strategy(nonce_strategy);
+                    status(&control)
+                }
+                _ => {
+                    let error = "nonce strategy should be sequential or random";
+                    respond(StatusCode::BAD_REQUEST, json!({ "error": error }))
+                }
+            },
+            _ => respond(StatusCode::NOT_FOUND, JsonValue::Null),
+        }
+    }))
+}
+
+fn status(control: &Control) -> Response<Body> {
+    let nonce_strategy = match control.nonce_strategy() {
+        NonceStrategy::Sequential => "sequential",
+        NonceStrategy::Random => "random",
+    };
+    respond(
+        StatusCode::OK,
+        json!({
+            "paused": control.is_paused(),
+            "jobs": control.jobs(),
+            "threads": control.threads(),
+            "nonceStrategy": nonce_strategy,
+        }),
+    )
+}
+
+fn respond(status: StatusCode, body: JsonValue) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response
+}
@@ -14,19 +14,28 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::sync::Arc;
-use std::thread::spawn;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{sleep, spawn};
+use std::time::Duration;
 
-use ethereum_types::{clean_0x, H256};
-use futures::future;
+use ethereum_types::{clean_0x, H256, U256};
+use futures::future::{self, Loop};
+use futures::sync::oneshot;
 use hyper::header::HeaderValue;
-use hyper::rt::{run, Future, Stream};
+use hyper::rt::{Future, Stream};
 use hyper::service::service_fn;
 use hyper::{self, Body, Client, Method, Request, Response, Server, StatusCode};
 use rustc_hex::ToHex;
-use serde_json;
+use serde_json::{self, Value as JsonValue};
+use tokio::runtime::current_thread;
 
-use super::super::worker::{work, Worker};
+use super::super::control::Control;
+use super::super::worker::{work, Lane, Worker};
+use super::control;
+use super::ipc::{self, UnixConnector};
 use super::RpcRunner;
 
 #[derive(Deserialize)]
@@ -35,81 +44,285 @@ pub struct Job {
     pub result: (String, String),
 }
 
+/// Where the miner gets work from.
+#[derive(Clone, Debug)]
+pub enum Listen {
+    /// Work is pushed to 127.0.0.1:port.
+    Port(u16),
+    /// Work is pushed to a Unix domain socket, created with the given file mode.
+    Unix {
+        path: PathBuf,
+        mode: u32,
+    },
+    /// `miner_getWork` is polled on the node's IPC socket.
+    Ipc {
+        path: PathBuf,
+        interval: Duration,
+    },
+}
+
+/// Where solutions are submitted.
+#[derive(Clone, Debug)]
+pub enum Endpoint {
+    /// HTTP JSON-RPC on 127.0.0.1:port.
+    Port(u16),
+    /// HTTP JSON-RPC over a Unix domain socket.
+    Unix(PathBuf),
+    /// The node's IPC JSON-RPC socket.
+    Ipc(PathBuf),
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Endpoint::Port(port) => write!(f, "127.0.0.1:{}", port),
+            Endpoint::Unix(ref path) => write!(f, "unix:{}", path.display()),
+            Endpoint::Ipc(ref path) => write!(f, "ipc:{}", path.display()),
+        }
+    }
+}
+
+/// How a solution is delivered when several submission endpoints are configured.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SubmitPolicy {
+    /// Send the solution to every endpoint at once.
+    Broadcast,
+    /// Try the endpoints in order until one of them accepts the solution.
+    Failover,
+}
+
 #[derive(Clone)]
 pub struct Config {
-    pub listen_port: u16,
-    pub submitting_port: u16,
+    pub listen: Listen,
+    pub submitting_endpoints: Vec<Endpoint>,
+    pub submit_policy: SubmitPolicy,
+}
+
+/// The result of submitting a solution to a single endpoint.
+#[derive(Debug, PartialEq)]
+pub enum Submission {
+    Accepted,
+    /// The node answered, but refused the solution.
+    Rejected(String),
+    /// The node could not be reached or answered with garbage.
+    Failed(String),
+}
+
+/// The running job most recently started from a pushed work.
+pub struct CurrentJob {
+    hash: H256,
+    target: Arc<RwLock<U256>>,
 }
 
+pub type SharedJob = Arc<Mutex<Option<CurrentJob>>>;
+
 pub struct Runner {
-    listen_port: u16,
-    submitting_port: u16,
+    listen: Listen,
+    submitting_endpoints: Vec<Endpoint>,
+    submit_policy: SubmitPolicy,
 }
 
 impl Runner {
     pub fn new(config: &Config) -> Self {
         Self {
-            listen_port: config.listen_port,
-            submitting_port: config.submitting_port,
+            listen: config.listen.clone(),
+            submitting_endpoints: config.submitting_endpoints.clone(),
+            submit_policy: config.submit_policy,
         }
     }
 }
 
 impl RpcRunner for Runner {
-    fn run(&self, recruiter: Arc<Fn() -> Box<Worker> + Send + Sync>, jobs: usize) {
-        let submit_port = self.submitting_port;
-        let addr = ([127, 0, 0, 1], self.listen_port).into();
-        let server = Server::bind(&addr)
-            .serve(move || {
-                let recruiter = Arc::clone(&recruiter);
-                service_fn(move |req| {
-                    let worker = recruiter();
-                    get_work(worker, jobs, req, submit_port)
-                })
-            })
-            .map_err(|e| error!("server error: {}", e));
-        info!("Server started, listening on {:?}", addr);
-        info!("It will submit to 127.0.0.1:{}", submit_port);
-        info!("The maximum number of parallel jobs is {}", jobs);
+    fn run(&self, recruiter: Arc<Fn() -> Box<Worker> + Send + Sync>, control: Arc<Control>) {
+        info!("The maximum number of parallel jobs is {}", control.jobs());
+        let handler = Handler {
+            recruiter,
+            control,
+            lane: Arc::new(Lane::default()),
+            current_job: Arc::new(Mutex::new(None)),
+            endpoints: Arc::new(self.submitting_endpoints.clone()),
+            submit_policy: self.submit_policy,
+        };
+        info!("It will submit to {:?} ({:?})", self.submitting_endpoints, self.submit_policy);
+
+        match self.listen {
+            Listen::Port(port) => {
+                let addr = ([127, 0, 0, 1], port).into();
+                let server = Server::bind(&addr)
+                    .serve(move || {
+                        let handler = handler.clone();
+                        service_fn(move |req| handler.get_work(req))
+                    })
+                    .map_err(|e| error!("server error: {}", e));
+                info!("Server started, listening on {:?}", addr);
 
-        hyper::rt::run(server);
+                hyper::rt::run(server);
+            }
+            Listen::Unix {
+                ref path,
+                mode,
+            } => {
+                let listener = match ipc::bind(path, mode) {
+                    Ok(listener) => listener,
+                    Err(err) => {
+                        error!("Failed to listen on {}: {}", path.display(), err);
+                        return
+                    }
+                };
+                let server = Server::builder(listener.incoming())
+                    .serve(move || {
+                        let handler = handler.clone();
+                        service_fn(move |req| handler.get_work(req))
+                    })
+                    .map_err(|e| error!("server error: {}", e));
+                info!("Server started, listening on {} ({:o})", path.display(), mode);
+
+                hyper::rt::run(server);
+            }
+            Listen::Ipc {
+                ref path,
+                interval,
+            } => {
+                info!("Polling work from {} every {:?}", path.display(), interval);
+                let request = json!({
+                    "jsonrpc": "2.0",
+                    "method": "miner_getWork",
+                    "params": [],
+                    "id": 1
+                });
+                loop {
+                    match ipc::call(path, &request) {
+                        Ok(res) => match parse_work(&res["result"]) {
+                            Some((hash, target)) => handler.start(hash, target),
+                            None => warn!("Invalid response to miner_getWork: {}", res),
+                        },
+                        Err(err) => warn!("Failed to get work from {}: {}", path.display(), err),
+                    }
+                    sleep(interval);
+                }
+            }
+        }
     }
 }
 
 type BoxFut = Box<Future<Item = Response<Body>, Error = hyper::Error> + Send>;
 
-fn get_work(worker: Box<Worker>, jobs: usize, req: Request<Body>, submit_port: u16) -> BoxFut {
-    let mut response = Response::new(Body::empty());
-    match (req.method(), req.uri().path()) {
-        (&Method::POST, "/") => {
-            Box::new(req.into_body().concat2().map(move |chunk| {
-                match serde_json::from_slice::<Job>(&chunk.into_bytes()) {
-                    Ok(rpc) => {
-                        // FIXME: don't unwrap while parsing incoming job
-                        let hash = clean_0x(&rpc.result.0).parse().unwrap();
-                        let target = clean_0x(&rpc.result.1).parse().unwrap();
-                        spawn(move || {
-                            if let Some(solution) = work(&hash, &target, worker, jobs) {
-                                submit(hash, solution, submit_port);
-                            }
-                        });
-                        *response.status_mut() = StatusCode::OK;
-                    }
-                    Err(_) => {
-                        *response.status_mut() = StatusCode::BAD_REQUEST;
+#[derive(Clone)]
+struct Handler {
+    recruiter: Arc<Fn() -> Box<Worker> + Send + Sync>,
+    control: Arc<Control>,
+    lane: Arc<Lane>,
+    current_job: SharedJob,
+    endpoints: Arc<Vec<Endpoint>>,
+    submit_policy: SubmitPolicy,
+}
+
+impl Handler {
+    fn get_work(&self, req: Request<Body>) -> BoxFut {
+        if req.uri().path().starts_with(control::PATH) {
+            return control::handle(&self.control, req)
+        }
+
+        let mut response = Response::new(Body::empty());
+        match (req.method(), req.uri().path()) {
+            (&Method::POST, "/") => {
+                let handler = self.clone();
+                Box::new(req.into_body().concat2().map(move |chunk| {
+                    match serde_json::from_slice::<Job>(&chunk.into_bytes()) {
+                        Ok(rpc) => {
+                            // FIXME: don't unwrap while parsing incoming job
+                            let hash = clean_0x(&rpc.result.0).parse().unwrap();
+                            let target = clean_0x(&rpc.result.1).parse().unwrap();
+                            handler.start(hash, target);
+                            *response.status_mut() = StatusCode::OK;
+                        }
+                        Err(_) => {
+                            *response.status_mut() = StatusCode::BAD_REQUEST;
+                        }
                     }
+                    response
+                }))
+            }
+            _ => {
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                Box::new(future::ok(response))
+            }
+        }
+    }
+
+    fn start(&self, hash: H256, target: U256) {
+        if let Some(target) = start_job(&self.current_job, hash, target) {
+            let recruiter = Arc::clone(&self.recruiter);
+            let control = Arc::clone(&self.control);
+            let lane = Arc::clone(&self.lane);
+            let endpoints = Arc::clone(&self.endpoints);
+            let submit_policy = self.submit_policy;
+            let current_job = Arc::clone(&self.current_job);
+            spawn(move || {
+                let solution = work(&hash, &target, &[], &recruiter, &control, &lane);
+                finish_job(&current_job, &target);
+                if let Some(solution) = solution {
+                    submit(hash, solution, &endpoints, submit_policy);
                 }
-                response
-            }))
+            });
         }
-        _ => {
-            *response.status_mut() = StatusCode::NOT_FOUND;
-            Box::new(future::ok(response))
+    }
+}
+
+/// Parses the work either as `[hash, target]` or as `miner_getWork` returns it.
+pub fn parse_work(work: &JsonValue) -> Option<(H256, U256)> {
+    let (hash, target) = if work.is_array() {
+        (work[0].as_str()?, work[1].as_str()?)
+    } else {
+        (work["powHash"].as_str()?, work["target"].as_str()?)
+    };
+    Some((H256::from_str(clean_0x(hash)).ok()?, U256::from_str(clean_0x(target)).ok()?))
+}
+
+/// Returns the target of a new job, or `None` if the pushed work is already being processed.
+///
+/// A push with the same hash but a different target updates the running job in place.
+pub fn start_job(current_job: &SharedJob, hash: H256, target: U256) -> Option<Arc<RwLock<U256>>> {
+    let mut current_job = current_job.lock().unwrap();
+    if let Some(ref job) = *current_job {
+        if job.hash == hash {
+            let mut current_target = job.target.write().unwrap();
+            if *current_target == target {
+                debug!("Ignoring the repeated work {}", hash);
+            } else {
+                info!("Updating the target of the running job {} to {}", hash, target);
+                *current_target = target;
+            }
+            return None
         }
     }
+
+    let target = Arc::new(RwLock::new(target));
+    *current_job = Some(CurrentJob {
+        hash,
+        target: Arc::clone(&target),
+    });
+    Some(target)
+}
+
+/// Forgets the job with `target` once it ends, so that the same work can be pushed again.
+pub fn finish_job(current_job: &SharedJob, target: &Arc<RwLock<U256>>) {
+    let mut current_job = current_job.lock().unwrap();
+    let finished = match *current_job {
+        Some(ref job) => Arc::ptr_eq(&job.target, target),
+        None => false,
+    };
+    if finished {
+        *current_job = None;
+    }
 }
 
-pub fn submit(hash: H256, solution: Vec<Vec<u8>>, port: u16) {
+pub fn submit(
+    hash: H256,
+    solution: Vec<Vec<u8>>,
+    endpoints: &[Endpoint],
+    policy: SubmitPolicy,
+) -> Vec<(Endpoint, Submission)> {
     let seal: Vec<_> = solution.iter().map(|bytes| format!("0x{}", bytes.to_hex())).collect();
 
     let json = json!({
@@ -121,13 +334,103 @@ pub fn submit(hash: H256, solution: Vec<Vec<u8>>, port: u16) {
         ],
         "id": null
     });
+
+    info!("Job finished with hash {}, seal {:?}", hash, seal);
+    let submissions: Box<Future<Item = Vec<(Endpoint, Submission)>, Error = ()>> = match policy {
+        SubmitPolicy::Broadcast => {
+            let requests: Vec<_> = endpoints
+                .iter()
+                .map(|endpoint| {
+                    let endpoint = endpoint.clone();
+                    submit_to(&endpoint, json.clone()).map(move |submission| (endpoint, submission))
+                })
+                .collect();
+            Box::new(future::join_all(requests))
+        }
+        SubmitPolicy::Failover => {
+            let endpoints = endpoints.to_vec();
+            Box::new(future::loop_fn((0, Vec::new()), move |(index, mut submissions): (usize, Vec<_>)| {
+                let endpoint = match endpoints.get(index) {
+                    Some(endpoint) => endpoint.clone(),
+                    None => return future::Either::A(future::ok(Loop::Break(submissions))),
+                };
+                future::Either::B(submit_to(&endpoint, json.clone()).map(move |submission| {
+                    let accepted = submission == Submission::Accepted;
+                    submissions.push((endpoint, submission));
+                    if accepted {
+                        Loop::Break(submissions)
+                    } else {
+                        Loop::Continue((index + 1, submissions))
+                    }
+                }))
+            }))
+        }
+    };
+
+    let submissions = current_thread::block_on_all(submissions).unwrap_or_default();
+    for (endpoint, submission) in &submissions {
+        match submission {
+            Submission::Accepted => info!("{} accepted the solution for {}", endpoint, hash),
+            Submission::Rejected(reason) => warn!("{} rejected the solution for {}: {}", endpoint, hash, reason),
+            Submission::Failed(reason) => warn!("Failed to submit the solution to {}: {}", endpoint, reason),
+        }
+    }
+    submissions
+}
+
+fn submit_to(endpoint: &Endpoint, json: JsonValue) -> Box<Future<Item = Submission, Error = ()> + Send> {
+    Box::new(request(endpoint, json).then(|result| match result {
+        Ok(res) => Ok(submission(&res)),
+        Err(err) => Ok(Submission::Failed(err)),
+    }))
+}
+
+/// Sends a JSON-RPC request to the node and returns its response.
+pub fn request(endpoint: &Endpoint, json: JsonValue) -> Box<Future<Item = JsonValue, Error = String> + Send> {
     let mut req = Request::new(Body::from(json.to_string()));
     *req.method_mut() = Method::POST;
-    *req.uri_mut() = format!("http://127.0.0.1:{}", port).parse().unwrap();
     req.headers_mut().insert("content-type", HeaderValue::from_str("application/json").unwrap());
 
-    info!("Job finished with hash {}, seal {:?}", hash, seal);
-    run(Client::new().request(req).map(|_| {}).map_err(|err| {
-        eprintln!("Error {}", err);
-    }));
+    let response = match *endpoint {
+        Endpoint::Port(port) => {
+            *req.uri_mut() = format!("http://127.0.0.1:{}", port).parse().unwrap();
+            Client::new().request(req)
+        }
+        Endpoint::Unix(ref path) => {
+            *req.uri_mut() = "http://localhost/".parse().unwrap();
+            Client::builder().build(UnixConnector::new(path.clone())).request(req)
+        }
+        Endpoint::Ipc(ref path) => {
+            // The IPC socket is used synchronously, so it gets a thread of its own.
+            let (tx, rx) = oneshot::channel();
+            let path = path.clone();
+            spawn(move || {
+                let _ = tx.send(ipc::call(&path, &json).map_err(|err| err.to_string()));
+            });
+            return Box::new(rx.then(|result| match result {
+                Ok(result) => result,
+                Err(_) => Err("the IPC request is canceled".to_string()),
+            }))
+        }
+    };
+
+    Box::new(response.map_err(|err| err.to_string()).and_then(|res| {
+        let status = res.status();
+        res.into_body().concat2().map_err(|err| err.to_string()).and_then(move |chunk| {
+            if !status.is_success() {
+                return Err(format!("HTTP status {}", status))
+            }
+            serde_json::from_slice::<JsonValue>(&chunk).map_err(|err| format!("invalid response: {}", err))
+        })
+    }))
+}
+
+fn submission(res: &JsonValue) -> Submission {
+    if res["result"] == true {
+        Submission::Accepted
+    } else if !res["error"].is_null() {
+        Submission::Rejected(res["error"].to_string())
+    } else {
+        Submission::Rejected(format!("unexpected response {}", res))
+    }
 }
@@ -0,0 +1,105 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net;
+use std::path::{Path, PathBuf};
+
+use futures::{Future, Poll};
+use hyper::client::connect::{Connect, Connected, Destination};
+use serde_json::{self, Value as JsonValue};
+use tokio::net::unix::ConnectFuture;
+use tokio::net::{UnixListener, UnixStream};
+
+/// Connects HTTP requests to a Unix domain socket regardless of the host in the URI.
+#[derive(Clone)]
+pub struct UnixConnector {
+    path: PathBuf,
+}
+
+impl UnixConnector {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+        }
+    }
+}
+
+impl Connect for UnixConnector {
+    type Transport = UnixStream;
+    type Error = io::Error;
+    type Future = Connecting;
+
+    fn connect(&self, _dst: Destination) -> Self::Future {
+        Connecting {
+            inner: UnixStream::connect(&self.path),
+        }
+    }
+}
+
+pub struct Connecting {
+    inner: ConnectFuture,
+}
+
+impl Future for Connecting {
+    type Item = (UnixStream, Connected);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let stream = try_ready!(self.inner.poll());
+        Ok((stream, Connected::new()).into())
+    }
+}
+
+/// Binds a Unix domain socket with `mode`, replacing a stale socket file.
+///
+/// Fails without touching the path if something other than a socket, or a socket still in use, is there.
+pub fn bind(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(ref metadata) if metadata.file_type().is_socket() => match net::UnixStream::connect(path) {
+            Ok(_) => {
+                let message = format!("{} is in use by another process", path.display());
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, message))
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+            Err(err) => return Err(err),
+        },
+        Ok(_) => {
+            let message = format!("{} exists and is not a socket", path.display());
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, message))
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Sends a JSON-RPC request to the node's IPC socket and waits for the response.
+pub fn call(path: &Path, request: &JsonValue) -> io::Result<JsonValue> {
+    let mut stream = net::UnixStream::connect(path)?;
+    stream.write_all(&serde_json::to_vec(request)?)?;
+    stream.write_all(b"\n")?;
+
+    let response = serde_json::Deserializer::from_reader(stream).into_iter().next();
+    match response {
+        Some(response) => Ok(response?),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the node closed the IPC socket")),
+    }
+}
@@ -14,22 +14,35 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+mod control;
 mod http;
-mod stratum;
+mod ipc;
+pub mod stratum;
+mod tcp;
+mod websocket;
 
 use std::sync::Arc;
 
+use super::control::Control;
 use super::worker::Worker;
 
-pub use self::http::{Config as HttpConfig, Runner as HttpRunner};
-pub use self::stratum::{Config as StratumConfig, Runner as StratumRunner};
+pub use self::http::{Config as HttpConfig, Endpoint, Listen, Runner as HttpRunner, SubmitPolicy};
+pub use self::stratum::{
+    Config as StratumConfig, CredentialStore, Identity as StratumIdentity, Pool as StratumPool,
+    ProxyConfig as StratumProxyConfig, ProxyRunner as StratumProxyRunner, Runner as StratumRunner,
+    ServerConfig as StratumServerConfig, ServerRunner as StratumServerRunner, Strictness, Verifier,
+};
+pub use self::websocket::{Config as WebSocketConfig, Runner as WebSocketRunner};
 
 #[derive(Clone)]
 pub enum RpcConfig {
     Http(HttpConfig),
     Stratum(StratumConfig),
+    StratumServer(StratumServerConfig),
+    StratumProxy(StratumProxyConfig),
+    WebSocket(WebSocketConfig),
 }
 
 pub trait RpcRunner: Send {
-    fn run(&self, Arc<Fn() -> Box<Worker> + Send + Sync>, usize);
+    fn run(&self, Arc<Fn() -> Box<Worker> + Send + Sync>, Arc<Control>);
 }
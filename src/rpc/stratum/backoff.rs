@@ -0,0 +1,54 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::cmp::min;
+use std::time::Duration;
+
+use rand::{thread_rng, Rng};
+
+/// Jittered exponential backoff.
+///
+/// The n-th delay is chosen randomly between the half and the whole of `initial * 2^n`, capped at `max`.
+#[derive(Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            attempts: 0,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let exp = self.initial.checked_mul(1 << min(self.attempts, 16)).unwrap_or(self.max);
+        let delay = min(exp, self.max);
+        self.attempts = self.attempts.saturating_add(1);
+
+        let millis = delay.as_secs() * 1000 + u64::from(delay.subsec_millis());
+        let half = millis / 2;
+        Duration::from_millis(half + thread_rng().gen_range(0, millis - half + 1))
+    }
+
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
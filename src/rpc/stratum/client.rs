@@ -14,51 +14,140 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::collections::{HashMap, VecDeque};
 use std::error::Error as StdError;
-use std::net::SocketAddr;
 use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-use bytes::{BufMut, BytesMut};
-use futures::sync::mpsc;
-use futures::{Async, Future, Poll, Stream};
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Async, AsyncSink, Future, Poll, Sink, Stream};
 use serde_json::Value as JsonValue;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::{ConnectFuture, TcpStream};
+use tokio::codec::Framed;
+use tokio::timer::{Delay, Interval, Timeout};
 
+use super::backoff::Backoff;
+use super::codec::Codec;
+use super::connect::{connect, Connecting, Stream as PoolStream};
 use super::dispatch::{Dispatch, NewDispatch};
-use super::error::Error;
+use super::error::{rpc_error, Error, Kind};
+use super::message::{Id, Message, Method, Strictness};
+use super::stats::Stats;
+use super::subscription::{SharedSubscription, Subscription};
+use super::tls::Tls;
 
-type Tx = mpsc::UnboundedSender<JsonValue>;
+pub type Tx = mpsc::UnboundedSender<JsonValue>;
 type Rx = mpsc::UnboundedReceiver<JsonValue>;
+/// The name of the worker the pool authorized on the current connection.
+pub type SharedWorker = Arc<RwLock<Option<String>>>;
+/// The requests made with `Handle::call` that wait for a response, by their id.
+type Pending = Arc<Mutex<HashMap<usize, oneshot::Sender<JsonValue>>>>;
+
+const INITIAL_BACKOFF_MS: u64 = 500;
+const MAX_BACKOFF_SECS: u64 = 60;
+pub const CONNECT_TIMEOUT_SECS: u64 = 10;
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+const REQUEST_TIMEOUT_SECS: u64 = 30;
+const SUBSCRIBE_ID: u64 = 1;
+pub const AUTHORIZE_ID: u64 = 2;
+const USER_AGENT: &str = concat!("codechain-miner/", env!("CARGO_PKG_VERSION"));
 
-#[derive(Debug)]
 pub struct Client<D> {
     serve: Serve<D>,
 }
 
-#[derive(Debug)]
+/// A pool endpoint with the credentials used for it.
+#[derive(Clone)]
+pub struct Pool {
+    /// `host:port` of the pool.
+    pub addr: String,
+    pub id: String,
+    pub pwd: String,
+    /// Secures the connection if set.
+    pub tls: Option<Arc<Tls>>,
+}
+
 pub struct Builder {
-    id: String,
-    pwd: String,
-    connect: Option<ConnectFuture>,
+    pools: Vec<Pool>,
+    connect_timeout: Duration,
+    backoff: Backoff,
+    reconnect: Option<mpsc::UnboundedReceiver<()>>,
+    max_connect_failures: usize,
+    primary_check: Option<Interval>,
+    subscription: SharedSubscription,
+    worker: SharedWorker,
+    stats: Arc<Stats>,
+    tx: Tx,
+    rx: Rx,
+    max_frame_size: usize,
+    idle_timeout: Option<Duration>,
+    ping_interval: Option<Duration>,
+    pending: Pending,
+    request_timeout: Duration,
+    strictness: Strictness,
+    events: Option<mpsc::UnboundedSender<Event>>,
 }
 
-impl Client<()> {
-    pub fn bind(addr: &SocketAddr, id: String, pwd: String) -> Builder {
-        let connect = TcpStream::connect(addr);
-        Client::builder(id, pwd, connect)
-    }
+/// What the pool asked of the client beyond mining, or what happened to the connection, for embedders to act on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// The pool sent a message for the person running the miner.
+    Message(String),
+    /// The pool asked the client to reconnect to `addr` after `wait`.
+    Reconnect {
+        addr: String,
+        wait: Duration,
+    },
+    /// The connection to the pool was lost, and the pool will not answer the requests sent on it.
+    Disconnected,
+}
 
-    fn builder(id: String, pwd: String, connect: ConnectFuture) -> Builder {
+/// Sends requests and notifications to the pool of a client.
+#[derive(Clone)]
+pub struct Handle {
+    tx: Tx,
+    stats: Arc<Stats>,
+    pending: Pending,
+    timeout: Duration,
+}
+
+impl Client<()> {
+    /// Connects to the first pool, failing over to the next ones in order. The client fails if `pools` is empty.
+    pub fn bind(pools: Vec<Pool>) -> Builder {
+        let (tx, rx) = mpsc::unbounded();
         Builder {
-            id,
-            pwd,
-            connect: Some(connect),
+            pools,
+            connect_timeout: Duration::from_secs(CONNECT_TIMEOUT_SECS),
+            backoff: Backoff::new(Duration::from_millis(INITIAL_BACKOFF_MS), Duration::from_secs(MAX_BACKOFF_SECS)),
+            reconnect: None,
+            max_connect_failures: 1,
+            primary_check: None,
+            subscription: Arc::new(RwLock::new(None)),
+            worker: Arc::new(RwLock::new(None)),
+            stats: Default::default(),
+            tx,
+            rx,
+            max_frame_size: MAX_FRAME_SIZE,
+            idle_timeout: None,
+            ping_interval: None,
+            pending: Default::default(),
+            request_timeout: Duration::from_secs(REQUEST_TIMEOUT_SECS),
+            strictness: Strictness::Lenient,
+            events: None,
         }
     }
 }
 
 impl<D> Client<D> {
+    pub fn handle(&self) -> Handle {
+        Handle {
+            tx: self.serve.tx.clone(),
+            stats: Arc::clone(&self.serve.stats),
+            pending: Arc::clone(&self.serve.pending),
+            timeout: self.serve.request_timeout,
+        }
+    }
+
     pub fn execute<F>(&self, fut: F) -> StdResult<(), Error>
     where
         F: Future<Item = (), Error = ()> + Send + 'static, {
@@ -88,42 +177,185 @@ where
     }
 }
 
-#[derive(Debug)]
 enum State {
-    Connecting,
+    Connecting(Connecting),
+    Subscribing,
     Authenticating,
     Working,
+    /// Waiting to reconnect after the connection is lost.
+    Waiting(Delay),
 }
 
-#[derive(Debug)]
 pub struct Serve<D> {
-    id: String,
-    pwd: String,
-    connect: ConnectFuture,
-    socket: Option<TcpStream>,
-    rd: BytesMut,
-    wr: BytesMut,
+    pools: Vec<Pool>,
+    /// The index of the pool in use.
+    current: usize,
+    /// The address the pool moved the client to with `client.reconnect`, used instead of its own.
+    redirect: Option<String>,
+    /// Consecutive connection failures to the current pool.
+    connect_failures: usize,
+    max_connect_failures: usize,
+    primary_check: Option<Interval>,
+    /// A connection attempt to the primary pool while another pool is in use.
+    probe: Option<Connecting>,
+    /// How long connecting to each address of a pool may take.
+    connect_timeout: Duration,
+    socket: Option<Framed<PoolStream, Codec>>,
+    max_frame_size: usize,
     rx: Rx,
+    /// A message taken from `rx` that the socket could not take yet.
+    unsent: Option<JsonValue>,
+    /// Messages that arrived before the handshake was over.
+    held: VecDeque<JsonValue>,
+    idle_timeout: Option<Duration>,
+    /// Expires when the pool has sent no request or notification for `idle_timeout`.
+    idle: Option<Delay>,
+    ping_interval: Option<Duration>,
+    ping: Option<Interval>,
+    /// The id of the last `mining.ping`, whose response is not dispatched.
+    ping_id: Option<usize>,
+    pending: Pending,
+    request_timeout: Duration,
+    strictness: Strictness,
+    events: Option<mpsc::UnboundedSender<Event>>,
     tx: Tx,
     new_dispatch: D,
     state: State,
+    backoff: Backoff,
+    reconnect: Option<mpsc::UnboundedReceiver<()>>,
+    stats: Arc<Stats>,
+    subscription: SharedSubscription,
+    worker: SharedWorker,
+    /// The session to resume when reconnecting to the same pool.
+    session_id: Option<String>,
 }
 
 impl Builder {
-    pub fn serve<D>(mut self, new_dispatch: D) -> Client<D> {
-        let connect = self.connect.take().unwrap();
-        let (tx, rx) = mpsc::unbounded();
+    /// Reconnects immediately whenever `reconnect` yields.
+    pub fn reconnect_on(mut self, reconnect: mpsc::UnboundedReceiver<()>) -> Self {
+        self.reconnect = Some(reconnect);
+        self
+    }
+
+    /// Sends what the pool asks of the client beyond mining to `events`.
+    pub fn events_to(mut self, events: mpsc::UnboundedSender<Event>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// How closely the `client.*` requests of the pool have to follow the protocol.
+    pub fn strictness(mut self, strictness: Strictness) -> Self {
+        self.strictness = strictness;
+        self
+    }
+
+    /// Moves to the next pool after `max_connect_failures` failed connections or an authorization failure,
+    /// and checks every `primary_check_interval` whether the first pool is reachable again.
+    pub fn failover(mut self, max_connect_failures: usize, primary_check_interval: Duration) -> Self {
+        self.max_connect_failures = max_connect_failures.max(1);
+        self.primary_check = Some(Interval::new_interval(primary_check_interval));
+        self
+    }
+
+    /// The extranonce the pool assigns to the current connection.
+    pub fn subscription(&self) -> SharedSubscription {
+        Arc::clone(&self.subscription)
+    }
+
+    /// The worker the pool authorized on the current connection.
+    pub fn worker(&self) -> SharedWorker {
+        Arc::clone(&self.worker)
+    }
+
+    /// The counters of the client.
+    pub fn stats(&self) -> Arc<Stats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Sends messages to the pool. Messages sent while disconnected are sent after reconnecting, while the ones still
+    /// queued when a connection is lost are dropped.
+    pub fn sender(&self) -> Tx {
+        self.tx.clone()
+    }
+
+    /// Treats a message longer than `max_frame_size` bytes as a protocol error.
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Reconnects when the pool sends nothing but answers, such as those to pings and shares, for `idle_timeout`.
+    /// Zero turns it off.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout).filter(|idle_timeout| *idle_timeout > Duration::from_secs(0));
+        self
+    }
+
+    /// Sends `mining.ping` every `ping_interval` while working, so that a live pool always has something to answer.
+    /// Zero turns it off.
+    pub fn ping_interval(mut self, ping_interval: Duration) -> Self {
+        self.ping_interval = Some(ping_interval).filter(|ping_interval| *ping_interval > Duration::from_secs(0));
+        self
+    }
+
+    /// Gives up connecting to an address of a pool after `connect_timeout`.
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Fails a `Handle::call` that the pool does not answer within `request_timeout`.
+    pub fn request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    pub fn handle(&self) -> Handle {
+        Handle {
+            tx: self.tx.clone(),
+            stats: Arc::clone(&self.stats),
+            pending: Arc::clone(&self.pending),
+            timeout: self.request_timeout,
+        }
+    }
+
+    pub fn serve<D>(self, new_dispatch: D) -> Client<D> {
+        let connect = match self.pools.first() {
+            Some(pool) => connect(&pool.addr, pool.tls.as_ref(), self.connect_timeout),
+            None => Box::new(future::err(Error::new_connect("No pool to connect to"))),
+        };
         let serve = Serve {
-            id: self.id,
-            pwd: self.pwd,
-            connect,
+            pools: self.pools,
+            current: 0,
+            redirect: None,
+            connect_failures: 0,
+            max_connect_failures: self.max_connect_failures,
+            primary_check: self.primary_check,
+            probe: None,
+            connect_timeout: self.connect_timeout,
             socket: None,
-            rd: BytesMut::new(),
-            wr: BytesMut::new(),
-            rx,
-            tx,
+            max_frame_size: self.max_frame_size,
+            rx: self.rx,
+            unsent: None,
+            held: VecDeque::new(),
+            idle_timeout: self.idle_timeout,
+            idle: None,
+            ping_interval: self.ping_interval,
+            ping: None,
+            ping_id: None,
+            pending: self.pending,
+            request_timeout: self.request_timeout,
+            strictness: self.strictness,
+            events: self.events,
+            tx: self.tx,
             new_dispatch,
-            state: State::Connecting,
+            state: State::Connecting(connect),
+            backoff: self.backoff,
+            reconnect: self.reconnect,
+            stats: self.stats,
+            subscription: self.subscription,
+            worker: self.worker,
+            session_id: None,
         };
 
         Client {
@@ -134,9 +366,171 @@ impl Builder {
 
 impl<D> Serve<D> {
     fn connected(&mut self) -> StdResult<bool, Error> {
-        if let Async::Ready(s) = self.connect.poll().map_err(|e| Error::new_connect(e))? {
-            self.socket = Some(s);
-            info!("Successfully connected");
+        let socket = match self.state {
+            State::Connecting(ref mut connect) => connect.poll()?,
+            _ => unreachable!(),
+        };
+        if let Async::Ready(s) = socket {
+            self.attach(s);
+            info!("Successfully connected to {}", self.addr());
+            return Ok(true)
+        }
+
+        Ok(false)
+    }
+
+    fn attach(&mut self, stream: PoolStream) {
+        self.socket = Some(Framed::new(stream, Codec::new(self.max_frame_size)));
+        self.idle = self.idle_timeout.map(|idle_timeout| Delay::new(Instant::now() + idle_timeout));
+        self.ping = self.ping_interval.map(|interval| Interval::new(Instant::now() + interval, interval));
+        self.ping_id = None;
+    }
+
+    fn pool(&self) -> &Pool {
+        &self.pools[self.current]
+    }
+
+    /// The address to connect to, which is the pool's own unless the pool moved the client.
+    fn addr(&self) -> &str {
+        self.redirect.as_ref().unwrap_or(&self.pool().addr)
+    }
+
+    fn connect(&self) -> State {
+        State::Connecting(connect(self.addr(), self.pool().tls.as_ref(), self.connect_timeout))
+    }
+
+    /// Moves to the next pool in the list, if there is one.
+    fn fail_over(&mut self) {
+        if self.pools.len() == 1 {
+            return
+        }
+        self.current = (self.current + 1) % self.pools.len();
+        self.redirect = None;
+        self.connect_failures = 0;
+        self.session_id = None;
+        self.backoff.reset();
+        warn!("Failing over to {}", self.pool().addr);
+    }
+
+    /// Returns a socket connected to the primary pool once it is reachable again.
+    fn primary_is_back(&mut self) -> Option<PoolStream> {
+        if self.current == 0 {
+            self.probe = None;
+            return None
+        }
+
+        let mut check = false;
+        if let Some(ref mut primary_check) = self.primary_check {
+            while let Ok(Async::Ready(Some(_))) = primary_check.poll() {
+                check = true;
+            }
+        }
+        if check && self.probe.is_none() {
+            debug!("Checking whether {} is back", self.pools[0].addr);
+            self.probe = Some(connect(&self.pools[0].addr, self.pools[0].tls.as_ref(), self.connect_timeout));
+        }
+
+        let probed = match self.probe {
+            Some(ref mut probe) => probe.poll(),
+            None => return None,
+        };
+        match probed {
+            Ok(Async::Ready(socket)) => {
+                self.probe = None;
+                Some(socket)
+            }
+            Ok(Async::NotReady) => None,
+            Err(_) => {
+                self.probe = None;
+                None
+            }
+        }
+    }
+
+    fn reconnect_requested(&mut self) -> bool {
+        let mut requested = false;
+        if let Some(ref mut reconnect) = self.reconnect {
+            while let Ok(Async::Ready(Some(()))) = reconnect.poll() {
+                requested = true;
+            }
+        }
+        requested
+    }
+
+    fn disconnect(&mut self) {
+        if self.socket.take().is_some() {
+            // The messages queued for the old connection are dropped, and the pool will not answer its requests.
+            self.unsent = None;
+            while let Ok(Async::Ready(Some(message))) = self.rx.poll() {
+                debug!("Drop a message queued for the lost connection: {}", message);
+            }
+            self.pending.lock().unwrap().clear();
+            self.emit(Event::Disconnected);
+        }
+        self.held.clear();
+        self.idle = None;
+        self.ping = None;
+        *self.subscription.write().unwrap() = None;
+        *self.worker.write().unwrap() = None;
+    }
+
+    /// Drops the connection and schedules a new one after a backoff.
+    fn wait_reconnect(&mut self, err: &Error) {
+        self.disconnect();
+        match *err.kind() {
+            Kind::Connect | Kind::Tls if self.redirect.is_some() => {
+                warn!("Failed to connect to {}, where the pool moved the client. Returning to the pool", self.addr());
+                self.redirect = None;
+            }
+            Kind::Connect | Kind::Tls => {
+                self.connect_failures += 1;
+                if self.connect_failures >= self.max_connect_failures {
+                    self.fail_over();
+                }
+            }
+            Kind::Authenticate => self.fail_over(),
+            _ => {}
+        }
+        let delay = self.backoff.next_delay();
+        let count = self.stats.reconnected();
+        warn!("Connection lost: {}. Reconnecting in {:?} (reconnection #{})", err, delay, count);
+        self.state = State::Waiting(Delay::new(Instant::now() + delay));
+    }
+
+    fn subscribe(&mut self) -> Poll<(), Error> {
+        let mut params = vec![json!(USER_AGENT)];
+        if let Some(ref session_id) = self.session_id {
+            params.push(json!(session_id));
+        }
+        let subscribe_request = json!({
+            "jsonrpc": "2.0",
+            "id": SUBSCRIBE_ID,
+            "method": "mining.subscribe",
+            "params": params,
+        });
+        debug!("Send a subscription message");
+        self.send(subscribe_request)
+    }
+
+    /// Pools without extranonces may not support `mining.subscribe`, so its failure only leaves the nonce whole.
+    /// An invalid subscription is a protocol error, though.
+    fn subscribed(&mut self) -> StdResult<bool, Error> {
+        while let Async::Ready(res) = self.read()? {
+            // A request of the pool may have the same id.
+            if res["id"] != SUBSCRIBE_ID || !res["method"].is_null() {
+                self.hold(res);
+                continue
+            }
+            if !res["error"].is_null() || res["result"].is_null() {
+                warn!("Failed to subscribe: {}. Mining without an extranonce", res["error"]);
+                self.session_id = None;
+                return Ok(true)
+            }
+            // An extranonce the client cannot use would make every share invalid.
+            let subscription = Subscription::from_result(&res["result"]).map_err(Error::new_protocol)?;
+            info!("Subscribed with extranonce1 {:?}", subscription.extranonce1);
+            self.session_id = subscription.session_id.clone();
+            *self.subscription.write().unwrap() = Some(subscription);
             return Ok(true)
         }
 
@@ -146,83 +540,308 @@ impl<D> Serve<D> {
     fn authenticate(&mut self) -> Poll<(), Error> {
         let auth_request = json!({
             "jsonrpc": "2.0",
-            "id": 1,
+            "id": AUTHORIZE_ID,
             "method": "mining.authorize",
-            "params": [self.id, self.pwd]
+            "params": [self.pool().id, self.pool().pwd]
         });
         debug!("Send an authentication message");
-        self.write(auth_request)
+        self.send(auth_request)
     }
 
     fn authenticated(&mut self) -> StdResult<bool, Error> {
-        if let Async::Ready(res) = self.read()? {
-            if res["id"] == 1 && res["result"] == true {
+        while let Async::Ready(res) = self.read()? {
+            if res["id"] != AUTHORIZE_ID || !res["method"].is_null() {
+                self.hold(res);
+                continue
+            }
+            if res["error"].is_null() && res["result"] == true {
                 info!("Successfully authenticated");
+                *self.worker.write().unwrap() = Some(self.pool().id.clone());
                 return Ok(true)
-            } else {
-                return Err(Error::new_authenticate())
             }
+            let cause = if res["error"].is_null() {
+                format!("{} refused the worker {}", self.pool().addr, self.pool().id)
+            } else {
+                match rpc_error(&res["error"]) {
+                    (Some(code), message) => format!("{} (code {})", message, code),
+                    (None, message) => message,
+                }
+            };
+            return Err(Error::new_authenticate(cause))
         }
 
         Ok(false)
     }
 
+    /// Keeps a message that arrived during the handshake, to dispatch it once the client is working.
+    fn hold(&mut self, message: JsonValue) {
+        debug!("Hold a message until the handshake is over: {}", message);
+        self.held.push_back(message);
+    }
+
     fn working(&mut self) -> Poll<Dispatcher<D::Future>, Error>
     where
         D: NewDispatch, {
-        if let Async::Ready(Some(json_value)) = self.rx.poll().unwrap() {
-            debug!("Send a message: {:?}", json_value);
-            self.write(json_value)?;
-        }
+        self.send_ping()?;
+        self.flush()?;
 
-        if let Async::Ready(req) = self.read()? {
+        loop {
+            let req = match self.held.pop_front() {
+                Some(req) => req,
+                None => try_ready!(self.read()),
+            };
+            if self.ping_id.is_some() && req["id"].as_u64().map(|id| id as usize) == self.ping_id {
+                debug!("The pool answered the ping: {}", req);
+                self.ping_id = None;
+                continue
+            }
+            let caller = match req["id"].as_u64() {
+                Some(id) if req["method"].is_null() => self.pending.lock().unwrap().remove(&(id as usize)),
+                _ => None,
+            };
+            if let Some(caller) = caller {
+                let _ = caller.send(req);
+                continue
+            }
+            if req["method"].as_str().map_or(false, |method| method.starts_with("client.")) && self.control(&req) {
+                if self.socket.is_none() {
+                    // The pool moved the client elsewhere.
+                    return Ok(Async::NotReady)
+                }
+                continue
+            }
             return Ok(Async::Ready(Dispatcher {
                 future: self.new_dispatch.new_dispatch(),
                 req,
+                call: None,
                 tx: self.tx.clone(),
             }))
         }
+    }
 
-        Ok(Async::NotReady)
+    /// Handles `mining.ping` and the `client.*` requests of the pool. Returns false for the ones left to the
+    /// dispatcher.
+    fn control(&mut self, req: &JsonValue) -> bool {
+        let (id, method) = match Message::from_value(req, self.strictness) {
+            Ok(Message::Request {
+                id,
+                method,
+            }) => (Some(id), method),
+            Ok(Message::Notification(method)) => (None, method),
+            Ok(Message::Response(_)) => return false,
+            Err(err) => {
+                warn!("Invalid message from the pool: {}", err);
+                return true
+            }
+        };
+        match method {
+            Method::Reconnect {
+                host,
+                port,
+                wait,
+            } => self.redirect(host, port, wait),
+            Method::ShowMessage(message) => {
+                info!("Message from {}: {}", self.pool().addr, message);
+                self.emit(Event::Message(message));
+            }
+            Method::GetVersion => match id {
+                Some(id) => self.reply(id, json!(USER_AGENT)),
+                None => debug!("Ignore client.get_version without an id"),
+            },
+            Method::Ping => match id {
+                Some(id) => self.reply(id, json!("pong")),
+                None => debug!("Ignore mining.ping without an id"),
+            },
+            _ => return false,
+        }
+        true
     }
 
-    fn read(&mut self) -> Poll<JsonValue, Error> {
-        self.rd.reserve(1024);
-        let socket = self.socket.as_mut().unwrap();
-        let n = try_ready!(socket.read_buf(&mut self.rd).map_err(|e| Error::new_io(e)));
+    /// Reconnects to `host:port` after `wait`, as the pool asked with `client.reconnect`.
+    ///
+    /// A pool secured with TLS is only followed to another port, since its certificate is for its own host. The wait is
+    /// capped like the backoff.
+    fn redirect(&mut self, host: Option<String>, port: Option<u16>, wait: Duration) {
+        let wait = wait.min(Duration::from_secs(MAX_BACKOFF_SECS));
+        let (current_host, current_port) = {
+            let mut parts = self.addr().rsplitn(2, ':');
+            let port = parts.next().unwrap_or_default().to_string();
+            (parts.next().unwrap_or_default().to_string(), port)
+        };
+        let host = match host {
+            Some(ref host) if self.pool().tls.is_some() && *host != current_host => {
+                warn!("Ignore the host {} of client.reconnect from a pool secured with TLS", host);
+                current_host
+            }
+            Some(host) => host,
+            None => current_host,
+        };
+        let addr = match port {
+            Some(port) => format!("{}:{}", host, port),
+            None => format!("{}:{}", host, current_port),
+        };
 
-        if n == 0 {
-            return Err(Error::new_closed())
-        }
+        info!("{} asked to reconnect to {} in {:?}", self.pool().addr, addr, wait);
+        self.emit(Event::Reconnect {
+            addr: addr.clone(),
+            wait,
+        });
+        self.disconnect();
+        self.redirect = if addr == self.pool().addr {
+            None
+        } else {
+            Some(addr)
+        };
+        let now = Instant::now();
+        self.state = State::Waiting(Delay::new(now.checked_add(wait).unwrap_or(now)));
+    }
 
-        let pos = self.rd.windows(1).enumerate().find(|&(_, bytes)| bytes == b"\n").map(|(i, _)| i);
-        if let Some(pos) = pos {
-            let mut line = self.rd.split_to(pos + 1);
-            line.split_off(pos);
+    fn reply(&self, id: Id, result: JsonValue) {
+        let _ = self.tx.unbounded_send(json!({
+            "id": id,
+            "result": result,
+            "error": null,
+        }));
+    }
 
-            let req = String::from_utf8(line.to_vec()).expect("Response should be utf-8");
-            let ret = ::serde_json::from_str(&req).map_err(|_e| Error::new_incomplete())?;
-            return Ok(Async::Ready(ret))
+    fn emit(&self, event: Event) {
+        if let Some(ref events) = self.events {
+            let _ = events.unbounded_send(event);
         }
+    }
 
-        Ok(Async::NotReady)
+    fn send_ping(&mut self) -> StdResult<(), Error> {
+        let mut due = false;
+        if let Some(ref mut ping) = self.ping {
+            while let Async::Ready(Some(_)) = ping.poll().map_err(|_e| Error::new_execute())? {
+                due = true;
+            }
+        }
+        if due {
+            let id = self.stats.next_id();
+            self.ping_id = Some(id);
+            debug!("Send a ping");
+            let _ = self.tx.unbounded_send(json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "method": "mining.ping",
+                "params": [],
+            }));
+        }
+        Ok(())
     }
 
-    fn write(&mut self, value: JsonValue) -> Poll<(), Error> {
-        let mut req = ::serde_json::ser::to_vec(&value).map_err(|_e| Error::new_incomplete())?;
-        req.extend(b"\n");
+    fn read(&mut self) -> Poll<JsonValue, Error> {
+        let idle = match self.idle {
+            Some(ref mut idle) => idle.poll().map_err(|_e| Error::new_execute())?.is_ready(),
+            None => false,
+        };
+        if idle {
+            let cause = format!("{} sent no work for {:?}", self.pool().addr, self.idle_timeout.unwrap());
+            return Err(Error::new_timeout(cause))
+        }
 
-        self.wr.reserve(1024);
-        self.wr.put(req);
+        let socket = self.socket.as_mut().unwrap();
+        // Writes left over from earlier polls go out before waiting for the response to them.
+        socket.poll_complete()?;
+        let message = match try_ready!(socket.poll()) {
+            Some(message) => message,
+            None => return Err(Error::new_closed()),
+        };
+        // Answers to pings and shares show that the pool is alive, but not that it still sends work.
+        if message["method"].is_string() {
+            if let (Some(idle), Some(idle_timeout)) = (self.idle.as_mut(), self.idle_timeout) {
+                idle.reset(Instant::now() + idle_timeout);
+            }
+        }
+        Ok(Async::Ready(message))
+    }
 
+    /// Sends a message of the handshake, which is the first on a new connection.
+    fn send(&mut self, message: JsonValue) -> Poll<(), Error> {
         let socket = self.socket.as_mut().unwrap();
-        while !self.wr.is_empty() {
-            let n = try_ready!(socket.poll_write(&self.wr).map_err(|e| Error::new_io(e)));
-            assert!(n > 0);
-            let _ = self.wr.split_to(n);
+        match socket.start_send(message)? {
+            AsyncSink::Ready => socket.poll_complete(),
+            AsyncSink::NotReady(_) => unreachable!("Nothing is queued before the handshake"),
         }
+    }
 
-        Ok(Async::Ready(()))
+    /// Writes every message queued in `rx`, as far as the socket takes them.
+    fn flush(&mut self) -> Poll<(), Error> {
+        let socket = self.socket.as_mut().unwrap();
+        loop {
+            let message = match self.unsent.take() {
+                Some(message) => message,
+                None => match self.rx.poll().expect("Receiving from a channel never fails") {
+                    Async::Ready(Some(message)) => message,
+                    Async::Ready(None) | Async::NotReady => break,
+                },
+            };
+            debug!("Send a message: {:?}", message);
+            if let AsyncSink::NotReady(message) = socket.start_send(message)? {
+                self.unsent = Some(message);
+                break
+            }
+        }
+        socket.poll_complete()
+    }
+}
+
+impl Handle {
+    /// Sends a request to the pool and returns the result of its response.
+    ///
+    /// The request is sent once the client is working, so a call made while it is disconnected waits for the next
+    /// connection. Fails as rejected if the pool answers with an error, with a timeout if it does not answer in time,
+    /// or as closed if the connection is lost.
+    pub fn call(&self, method: &str, params: JsonValue) -> Box<Future<Item = JsonValue, Error = Error> + Send> {
+        let id = self.stats.next_id();
+        let (caller, response) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, caller);
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        });
+        if self.tx.unbounded_send(request).is_err() {
+            self.pending.lock().unwrap().remove(&id);
+            return Box::new(future::err(Error::new_closed()))
+        }
+
+        let pending = Arc::clone(&self.pending);
+        let timeout = self.timeout;
+        let method = method.to_string();
+        Box::new(
+            Timeout::new(response, timeout)
+                .map_err(move |err| {
+                    pending.lock().unwrap().remove(&id);
+                    if err.is_elapsed() {
+                        Error::new_timeout(format!("The pool did not answer {} in {:?}", method, timeout))
+                    } else if err.is_inner() {
+                        Error::new_closed()
+                    } else {
+                        Error::new_execute()
+                    }
+                })
+                .and_then(|response| {
+                    if response["error"].is_null() {
+                        return Ok(response["result"].clone())
+                    }
+                    let (code, message) = rpc_error(&response["error"]);
+                    Err(Error::new_rejected(code, message))
+                }),
+        )
+    }
+
+    /// Sends a notification, which the pool does not answer.
+    pub fn notify(&self, method: &str, params: JsonValue) -> StdResult<(), Error> {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "method": method,
+            "params": params,
+        });
+        self.tx.unbounded_send(notification).map_err(|_e| Error::new_closed())
     }
 }
 
@@ -234,13 +853,71 @@ where
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.pools.is_empty() {
+            return Err(Error::new_connect("No pool to connect to"))
+        }
+
+        if self.reconnect_requested() {
+            info!("Reconnecting on request");
+            self.disconnect();
+            self.state = self.connect();
+        }
+
+        if let Some(socket) = self.primary_is_back() {
+            info!("{} is back. Returning to it", self.pools[0].addr);
+            self.disconnect();
+            self.current = 0;
+            self.redirect = None;
+            self.connect_failures = 0;
+            self.session_id = None;
+            self.attach(socket);
+            self.subscribe()?;
+            self.state = State::Subscribing;
+        }
+
+        loop {
+            match self.poll_state() {
+                Err(ref err) if !self.is_fatal(err) => self.wait_reconnect(err),
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<D> Serve<D>
+where
+    D: NewDispatch,
+{
+    /// Errors after which reconnecting would not help.
+    fn is_fatal(&self, err: &Error) -> bool {
+        match *err.kind() {
+            Kind::Execute => true,
+            Kind::Authenticate => self.pools.len() == 1,
+            _ => false,
+        }
+    }
+
+    fn poll_state(&mut self) -> Poll<Option<Dispatcher<D::Future>>, Error> {
         loop {
             let next = match self.state {
-                State::Connecting => {
+                State::Waiting(ref mut delay) => {
+                    try_ready!(delay.poll().map_err(|_e| Error::new_execute()));
+                    info!("Reconnecting to {}", self.addr());
+                    self.connect()
+                }
+                State::Connecting(_) => {
                     if !self.connected()? {
                         return Ok(Async::NotReady)
                     }
 
+                    self.subscribe()?;
+                    State::Subscribing
+                }
+                State::Subscribing => {
+                    if !self.subscribed()? {
+                        return Ok(Async::NotReady)
+                    }
+
                     self.authenticate()?;
                     State::Authenticating
                 }
@@ -249,14 +926,21 @@ where
                         return Ok(Async::NotReady)
                     }
 
+                    self.backoff.reset();
+                    self.connect_failures = 0;
+                    self.stats.new_session();
                     State::Working
                 }
                 State::Working => {
                     if let Async::Ready(fut) = self.working()? {
                         return Ok(Async::Ready(Some(fut)))
                     }
+                    if let State::Working = self.state {
+                        return Ok(Async::NotReady)
+                    }
 
-                    return Ok(Async::NotReady)
+                    // The pool asked to reconnect, and the client waits for it.
+                    continue
                 }
             };
             self.state = next;
@@ -264,9 +948,14 @@ where
     }
 }
 
+/// The handling of a message by a dispatch.
+type Call = Box<Future<Item = Option<JsonValue>, Error = Box<StdError + Send + Sync>> + Send>;
+
+/// Handles a message of the pool with a new dispatch, and sends its answer to the pool.
 pub struct Dispatcher<F> {
     future: F,
     req: JsonValue,
+    call: Option<Call>,
     tx: Tx,
 }
 
@@ -274,19 +963,31 @@ impl<F, D> Future for Dispatcher<F>
 where
     F: Future<Item = D>,
     D: Dispatch,
-    D::Future: Send + 'static,
-    D::Error: Into<Box<StdError + Send + Sync>>,
+    D::Error: Into<Box<StdError + Send + Sync>> + 'static,
     D::Future: Future<Item = Option<JsonValue>, Error = D::Error> + Send + 'static,
 {
     type Item = ();
     type Error = ();
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(Async::Ready(ref mut dispatcher)) = self.future.poll() {
-            let mut fut = dispatcher.call(self.req.clone());
-            if let Ok(Async::Ready(Some(res))) = fut.poll() {
+        if self.call.is_none() {
+            let mut dispatch = match self.future.poll() {
+                Ok(Async::Ready(dispatch)) => dispatch,
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(_) => {
+                    warn!("Failed to make a dispatcher for {}", self.req);
+                    return Ok(Async::Ready(()))
+                }
+            };
+            self.call = Some(Box::new(dispatch.call(self.req.take()).map_err(Into::into)));
+        }
+        match self.call.as_mut().expect("The call is made above").poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(Some(res))) => {
                 let _ = self.tx.unbounded_send(res);
             }
+            Ok(Async::Ready(None)) => {}
+            Err(err) => warn!("Failed to handle a message of the pool: {}", err),
         }
         Ok(Async::Ready(()))
     }
@@ -0,0 +1,103 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::str;
+
+use bytes::{BufMut, BytesMut};
+use serde_json::{self, Value as JsonValue};
+use tokio::codec::{Decoder, Encoder};
+
+use super::error::Error;
+
+/// Frames JSON messages separated by newlines.
+#[derive(Debug)]
+pub struct Codec {
+    max_frame_size: usize,
+    /// Where to continue looking for a newline, so that a long line is not scanned again on every read.
+    next_index: usize,
+}
+
+impl Codec {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            max_frame_size,
+            next_index: 0,
+        }
+    }
+}
+
+impl Decoder for Codec {
+    type Item = JsonValue;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<JsonValue>, Error> {
+        loop {
+            let pos = match buf[self.next_index..].iter().position(|byte| *byte == b'\n') {
+                Some(offset) => self.next_index + offset,
+                None => {
+                    if buf.len() > self.max_frame_size {
+                        return Err(Error::new_frame_too_large(format!(
+                            "A message is longer than the limit of {} bytes",
+                            self.max_frame_size
+                        )))
+                    }
+                    self.next_index = buf.len();
+                    return Ok(None)
+                }
+            };
+            self.next_index = 0;
+            if pos > self.max_frame_size {
+                return Err(Error::new_frame_too_large(format!(
+                    "A message of {} bytes is longer than the limit of {} bytes",
+                    pos, self.max_frame_size
+                )))
+            }
+
+            let line = buf.split_to(pos + 1);
+            let line = str::from_utf8(&line[..pos])
+                .map_err(|err| Error::new_protocol(format!("A message is not valid UTF-8: {}", err)))?
+                .trim();
+            if line.is_empty() {
+                continue
+            }
+            return serde_json::from_str(line)
+                .map(Some)
+                .map_err(|err| Error::new_protocol(format!("Invalid JSON message {:?}: {}", line, err)))
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<JsonValue>, Error> {
+        match self.decode(buf)? {
+            Some(message) => Ok(Some(message)),
+            None if buf.iter().all(u8::is_ascii_whitespace) => Ok(None),
+            None => Err(Error::new_incomplete()),
+        }
+    }
+}
+
+impl Encoder for Codec {
+    type Item = JsonValue;
+    type Error = Error;
+
+    fn encode(&mut self, message: JsonValue, buf: &mut BytesMut) -> Result<(), Error> {
+        let message = serde_json::to_vec(&message)
+            .map_err(|err| Error::new_protocol(format!("Cannot serialize a message: {}", err)))?;
+        buf.reserve(message.len() + 1);
+        buf.put(message);
+        buf.put_u8(b'\n');
+        Ok(())
+    }
+}
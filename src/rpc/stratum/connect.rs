@@ -0,0 +1,118 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Future, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::timer::Timeout;
+use tokio_tls::TlsStream;
+use url::{Host, Url};
+
+use super::super::tcp;
+use super::error::Error;
+use super::tls::Tls;
+
+pub type Connecting = Box<Future<Item = Stream, Error = Error> + Send>;
+
+/// A connection to a pool, either plain or over TLS.
+pub enum Stream {
+    Tcp(TcpStream),
+    Tls(TlsStream<TcpStream>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.read(buf),
+            Stream::Tls(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.write(buf),
+            Stream::Tls(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.flush(),
+            Stream::Tls(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsyncRead for Stream {}
+
+impl AsyncWrite for Stream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            Stream::Tcp(ref mut stream) => AsyncWrite::shutdown(stream),
+            Stream::Tls(ref mut stream) => stream.shutdown(),
+        }
+    }
+}
+
+/// Parses `host:port`, `stratum+tcp://host:port` or `stratum+ssl://host:port` into the `host:port` to connect to
+/// and whether the connection uses TLS.
+pub fn parse_addr(addr: &str) -> Result<(String, bool), String> {
+    let url = if addr.contains("://") {
+        Url::parse(addr)
+    } else {
+        Url::parse(&format!("stratum+tcp://{}", addr))
+    }
+    .map_err(|err| format!("{}: {}", addr, err))?;
+
+    let tls = match url.scheme() {
+        "stratum+tcp" => false,
+        "stratum+ssl" => true,
+        scheme => return Err(format!("{}: unsupported scheme {}", addr, scheme)),
+    };
+    let host = match url.host() {
+        Some(Host::Domain(domain)) => domain.to_string(),
+        Some(Host::Ipv4(ip)) => ip.to_string(),
+        Some(Host::Ipv6(ip)) => format!("[{}]", ip),
+        None => return Err(format!("{}: no host", addr)),
+    };
+    match url.port() {
+        Some(port) => Ok((format!("{}:{}", host, port), tls)),
+        None => Err(format!("{}: no port", addr)),
+    }
+}
+
+/// Connects to the pool at `addr`, giving the TLS handshake, if any, another `timeout`.
+pub fn connect(addr: &str, tls: Option<&Arc<Tls>>, timeout: Duration) -> Connecting {
+    let socket = tcp::connect(addr, timeout).map_err(Error::new_connect);
+    match tls {
+        Some(tls) => {
+            let tls = Arc::clone(tls);
+            Box::new(socket.and_then(move |socket| {
+                Timeout::new(tls.handshake(socket), timeout).map(Stream::Tls).map_err(|err| match err.into_inner() {
+                    Some(err) => Error::new_tls(err),
+                    None => Error::new_tls("the TLS handshake timed out"),
+                })
+            }))
+        }
+        None => Box::new(socket.map(Stream::Tcp)),
+    }
+}
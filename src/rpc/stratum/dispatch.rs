@@ -21,6 +21,7 @@ use futures::{future, Future, IntoFuture};
 use serde_json::Value as JsonValue;
 
 use super::error::{Error, Never};
+use super::layer::{Layer, Layered};
 
 pub type Result = Box<Future<Item = Option<JsonValue>, Error = Error> + Send>;
 
@@ -83,6 +84,14 @@ pub trait NewDispatch {
 
     /// Create a new `Dispatch`.
     fn new_dispatch(&self) -> Self::Future;
+
+    /// Wraps every `Dispatch` this creates in `layer`. The last layer added is the outermost one.
+    fn wrap<L>(self, layer: L) -> Layered<Self, L>
+    where
+        Self: Sized,
+        L: Layer<Self::Dispatch>, {
+        Layered::new(self, layer)
+    }
 }
 
 impl<F, R, D> NewDispatch for F
@@ -18,6 +18,8 @@ use std::error::Error as StdError;
 use std::fmt;
 use std::io;
 
+use serde_json::Value as JsonValue;
+
 type Cause = Box<StdError + Send + Sync>;
 
 /// Represents errors that can occur handling Stratum streams.
@@ -30,7 +32,7 @@ struct ErrorImpl {
     cause: Option<Cause>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Kind {
     /// A message reached EOF, but is not complete.
     Incomplete,
@@ -44,6 +46,19 @@ pub enum Kind {
     Authenticate,
     /// Error trying to call `Executor::execute`.
     Execute,
+    /// The remote sent a message that is not a valid JSON line.
+    Protocol,
+    /// The remote did not send anything in time.
+    Timeout,
+    /// The pool answered a request with an error.
+    Rejected {
+        code: Option<i64>,
+        message: String,
+    },
+    /// The TLS handshake failed or the certificate of the pool was not trusted.
+    Tls,
+    /// The remote sent a message longer than the limit.
+    FrameTooLarge,
 }
 
 impl Error {
@@ -56,6 +71,11 @@ impl Error {
         }
     }
 
+    /// What went wrong, for deciding whether to retry, fail over or give up.
+    pub fn kind(&self) -> &Kind {
+        &self.inner.kind
+    }
+
     pub fn new_io(cause: io::Error) -> Error {
         Error::new(Kind::Io, Some(cause.into()))
     }
@@ -72,13 +92,43 @@ impl Error {
         Error::new(Kind::Incomplete, None)
     }
 
-    pub fn new_authenticate() -> Error {
-        Error::new(Kind::Authenticate, None)
+    pub fn new_authenticate<E: Into<Cause>>(cause: E) -> Error {
+        Error::new(Kind::Authenticate, Some(cause.into()))
     }
 
     pub fn new_execute() -> Error {
         Error::new(Kind::Execute, None)
     }
+
+    pub fn new_timeout<E: Into<Cause>>(cause: E) -> Error {
+        Error::new(Kind::Timeout, Some(cause.into()))
+    }
+
+    pub fn new_protocol<E: Into<Cause>>(cause: E) -> Error {
+        Error::new(Kind::Protocol, Some(cause.into()))
+    }
+
+    pub fn new_rejected(code: Option<i64>, message: String) -> Error {
+        let cause = match code {
+            Some(code) => format!("{} (code {})", message, code),
+            None => message.clone(),
+        };
+        Error::new(
+            Kind::Rejected {
+                code,
+                message,
+            },
+            Some(cause.into()),
+        )
+    }
+
+    pub fn new_tls<E: Into<Cause>>(cause: E) -> Error {
+        Error::new(Kind::Tls, Some(cause.into()))
+    }
+
+    pub fn new_frame_too_large<E: Into<Cause>>(cause: E) -> Error {
+        Error::new(Kind::FrameTooLarge, Some(cause.into()))
+    }
 }
 
 impl fmt::Debug for Error {
@@ -106,6 +156,13 @@ impl StdError for Error {
             Kind::Authenticate => "an error occurred trying to authenticate",
             Kind::Execute => "executor failed to spawn task",
             Kind::Io => "an IO error occurred",
+            Kind::Protocol => "the remote violated the protocol",
+            Kind::Timeout => "the remote did not respond in time",
+            Kind::Rejected {
+                ..
+            } => "the pool rejected the request",
+            Kind::Tls => "the TLS handshake failed",
+            Kind::FrameTooLarge => "the remote sent a message that is too long",
         }
     }
 
@@ -114,6 +171,25 @@ impl StdError for Error {
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(cause: io::Error) -> Error {
+        Error::new_io(cause)
+    }
+}
+
+/// Splits the `error` of a response into its code and message.
+///
+/// Pools send either `[code, message, traceback]` or `{"code": code, "message": message}`. The message falls back to
+/// the whole error if it has none.
+pub fn rpc_error(error: &JsonValue) -> (Option<i64>, String) {
+    let (code, message) = if error.is_array() {
+        (error[0].as_i64(), error[1].as_str())
+    } else {
+        (error["code"].as_i64(), error["message"].as_str())
+    };
+    (code, message.map(str::to_string).unwrap_or_else(|| error.to_string()))
+}
+
 #[derive(Debug)]
 pub enum Never {}
 
@@ -0,0 +1,374 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+use serde_json::Value as JsonValue;
+use tokio::timer;
+
+use super::dispatch::{Dispatch, NewDispatch};
+use super::error::Error;
+
+type BoxError = Box<StdError + Send + Sync>;
+
+/// Wraps a `Dispatch` in another one, which adds some behavior around every message.
+pub trait Layer<D> {
+    type Dispatch: Dispatch;
+
+    fn layer(&self, inner: D) -> Self::Dispatch;
+}
+
+/// A `NewDispatch` whose dispatches are wrapped in a layer. Made by `NewDispatch::wrap`.
+pub struct Layered<N, L> {
+    inner: N,
+    layer: Arc<L>,
+}
+
+impl<N, L> Layered<N, L> {
+    pub fn new(inner: N, layer: L) -> Self {
+        Self {
+            inner,
+            layer: Arc::new(layer),
+        }
+    }
+}
+
+impl<N, L> NewDispatch for Layered<N, L>
+where
+    N: NewDispatch,
+    L: Layer<N::Dispatch>,
+{
+    type Error = <L::Dispatch as Dispatch>::Error;
+    type Dispatch = L::Dispatch;
+    type Future = MakeLayered<N::Future, L>;
+    type InitError = N::InitError;
+
+    fn new_dispatch(&self) -> Self::Future {
+        MakeLayered {
+            future: self.inner.new_dispatch(),
+            layer: Arc::clone(&self.layer),
+        }
+    }
+}
+
+pub struct MakeLayered<F, L> {
+    future: F,
+    layer: Arc<L>,
+}
+
+impl<F, L> Future for MakeLayered<F, L>
+where
+    F: Future,
+    L: Layer<F::Item>,
+{
+    type Item = L::Dispatch;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = try_ready!(self.future.poll());
+        Ok(Async::Ready(self.layer.layer(inner)))
+    }
+}
+
+/// What a message is, for the logs and the metrics.
+fn label(req: &JsonValue) -> String {
+    match req["method"].as_str() {
+        Some(method) => method.to_string(),
+        None => "response".to_string(),
+    }
+}
+
+/// Logs every message of the pool, with its answer and how long it took.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogLayer;
+
+impl<D: Dispatch> Layer<D> for LogLayer {
+    type Dispatch = Log<D>;
+
+    fn layer(&self, inner: D) -> Log<D> {
+        Log {
+            inner,
+        }
+    }
+}
+
+pub struct Log<D> {
+    inner: D,
+}
+
+impl<D: Dispatch> Dispatch for Log<D> {
+    type Error = BoxError;
+    type Future = LogFuture<D::Future>;
+
+    fn call(&mut self, req: JsonValue) -> Self::Future {
+        debug!("Dispatching {}", req);
+        LogFuture {
+            label: label(&req),
+            started: Instant::now(),
+            future: self.inner.call(req),
+        }
+    }
+}
+
+pub struct LogFuture<F> {
+    label: String,
+    started: Instant,
+    future: F,
+}
+
+impl<F> Future for LogFuture<F>
+where
+    F: Future<Item = Option<JsonValue>>,
+    F::Error: Into<BoxError>,
+{
+    type Item = Option<JsonValue>;
+    type Error = BoxError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.future.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(res)) => {
+                match res {
+                    Some(ref res) => debug!("Answered {} with {} in {:?}", self.label, res, self.started.elapsed()),
+                    None => debug!("Dispatched {} in {:?}", self.label, self.started.elapsed()),
+                }
+                Ok(Async::Ready(res))
+            }
+            Err(err) => {
+                let err = err.into();
+                debug!("Failed to dispatch {} in {:?}: {}", self.label, self.started.elapsed(), err);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Counters of the messages that went through a `MetricsLayer`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    dispatched: AtomicUsize,
+    answered: AtomicUsize,
+    failed: AtomicUsize,
+    finished: AtomicUsize,
+    /// The time spent on the finished messages, in microseconds.
+    busy: AtomicUsize,
+    /// The number of messages by method.
+    methods: Mutex<BTreeMap<String, usize>>,
+}
+
+impl Metrics {
+    /// The number of messages given to the dispatcher.
+    pub fn dispatched(&self) -> usize {
+        self.dispatched.load(Ordering::SeqCst)
+    }
+
+    /// The number of messages answered to the pool.
+    pub fn answered(&self) -> usize {
+        self.answered.load(Ordering::SeqCst)
+    }
+
+    /// The number of messages the dispatcher failed to handle.
+    pub fn failed(&self) -> usize {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    /// The number of messages of `method`. Responses to the requests of the client are counted as "response".
+    pub fn method(&self, method: &str) -> usize {
+        self.methods.lock().unwrap().get(method).cloned().unwrap_or(0)
+    }
+
+    /// The average time the finished messages took.
+    pub fn average_time(&self) -> Duration {
+        match self.finished.load(Ordering::SeqCst) {
+            0 => Duration::from_secs(0),
+            finished => Duration::from_micros((self.busy.load(Ordering::SeqCst) / finished) as u64),
+        }
+    }
+
+    fn started(&self, label: &str) {
+        self.dispatched.fetch_add(1, Ordering::SeqCst);
+        *self.methods.lock().unwrap().entry(label.to_string()).or_insert(0) += 1;
+    }
+
+    fn finished(&self, started: Instant) {
+        let elapsed = started.elapsed();
+        self.busy.fetch_add(elapsed.as_micros() as usize, Ordering::SeqCst);
+        self.finished.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} dispatched, {} answered, {} failed", self.dispatched(), self.answered(), self.failed())?;
+        let methods = self.methods.lock().unwrap();
+        if !methods.is_empty() {
+            let methods: Vec<_> = methods.iter().map(|(method, count)| format!("{}: {}", method, count)).collect();
+            write!(f, " ({})", methods.join(", "))?;
+        }
+        write!(f, ", {:?} on average", self.average_time())
+    }
+}
+
+/// Counts the messages of the pool, their answers and failures, and the time they took in `Metrics`.
+#[derive(Clone, Debug)]
+pub struct MetricsLayer {
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            metrics,
+        }
+    }
+}
+
+impl<D: Dispatch> Layer<D> for MetricsLayer {
+    type Dispatch = Measured<D>;
+
+    fn layer(&self, inner: D) -> Measured<D> {
+        Measured {
+            inner,
+            metrics: Arc::clone(&self.metrics),
+        }
+    }
+}
+
+pub struct Measured<D> {
+    inner: D,
+    metrics: Arc<Metrics>,
+}
+
+impl<D: Dispatch> Dispatch for Measured<D> {
+    type Error = D::Error;
+    type Future = MeasuredFuture<D::Future>;
+
+    fn call(&mut self, req: JsonValue) -> Self::Future {
+        self.metrics.started(&label(&req));
+        MeasuredFuture {
+            metrics: Arc::clone(&self.metrics),
+            started: Instant::now(),
+            future: self.inner.call(req),
+        }
+    }
+}
+
+pub struct MeasuredFuture<F> {
+    metrics: Arc<Metrics>,
+    started: Instant,
+    future: F,
+}
+
+impl<F> Future for MeasuredFuture<F>
+where
+    F: Future<Item = Option<JsonValue>>,
+{
+    type Item = Option<JsonValue>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = self.future.poll();
+        match result {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(Some(_))) => {
+                self.metrics.answered.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(Async::Ready(None)) => {}
+            Err(_) => {
+                self.metrics.failed.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        self.metrics.finished(self.started);
+        result
+    }
+}
+
+/// Fails the messages of the pool that are not handled within a duration.
+#[derive(Clone, Copy, Debug)]
+pub struct TimeoutLayer {
+    timeout: Duration,
+}
+
+impl TimeoutLayer {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+        }
+    }
+}
+
+impl<D: Dispatch> Layer<D> for TimeoutLayer {
+    type Dispatch = Timeout<D>;
+
+    fn layer(&self, inner: D) -> Timeout<D> {
+        Timeout {
+            inner,
+            timeout: self.timeout,
+        }
+    }
+}
+
+pub struct Timeout<D> {
+    inner: D,
+    timeout: Duration,
+}
+
+impl<D: Dispatch> Dispatch for Timeout<D> {
+    type Error = BoxError;
+    type Future = TimeoutFuture<D::Future>;
+
+    fn call(&mut self, req: JsonValue) -> Self::Future {
+        TimeoutFuture {
+            label: label(&req),
+            timeout: self.timeout,
+            future: timer::Timeout::new(self.inner.call(req), self.timeout),
+        }
+    }
+}
+
+pub struct TimeoutFuture<F> {
+    label: String,
+    timeout: Duration,
+    future: timer::Timeout<F>,
+}
+
+impl<F> Future for TimeoutFuture<F>
+where
+    F: Future<Item = Option<JsonValue>>,
+    F::Error: Into<BoxError>,
+{
+    type Item = Option<JsonValue>;
+    type Error = BoxError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.future.poll().map_err(|err| {
+            if err.is_elapsed() {
+                let message = format!("{} was not handled in {:?}", self.label, self.timeout);
+                Box::new(Error::new_timeout(message)) as BoxError
+            } else if err.is_inner() {
+                err.into_inner().expect("The error is of the inner future").into()
+            } else {
+                Box::new(err.into_timer().expect("The error is of the timer")) as BoxError
+            }
+        })
+    }
+}
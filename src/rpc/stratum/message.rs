@@ -0,0 +1,447 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::result::Result as StdResult;
+use std::str::FromStr;
+use std::time::Duration;
+
+use ethereum_types::{clean_0x, H256, U256};
+use rustc_hex::FromHex;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Map, Value as JsonValue};
+
+use super::error::{rpc_error, Error};
+
+/// How closely the messages of the remote have to follow the protocol.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strictness {
+    /// Rejects unknown methods, unknown fields, extra parameters and parameters that are not an array.
+    Strict,
+    /// Ignores unknown fields and extra parameters, treats parameters that are not an array as none, and keeps unknown
+    /// methods as `Method::Unknown`.
+    Lenient,
+}
+
+/// The id of a request, which its response has too.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Id {
+    Number(u64),
+    String(String),
+}
+
+impl Id {
+    pub fn as_number(&self) -> Option<u64> {
+        match *self {
+            Id::Number(id) => Some(id),
+            Id::String(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Id::Number(id) => write!(f, "{}", id),
+            Id::String(ref id) => f.write_str(id),
+        }
+    }
+}
+
+/// A message of either side of a stratum connection.
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    /// A request the remote expects a response to.
+    Request {
+        id: Id,
+        method: Method,
+    },
+    /// A request without an id, which is not answered.
+    Notification(Method),
+    Response(Response),
+}
+
+/// The methods of the requests and notifications, with their parameters.
+#[derive(Debug, PartialEq)]
+pub enum Method {
+    /// `mining.subscribe` with `[user_agent, session_id]`, both optional.
+    Subscribe {
+        user_agent: Option<String>,
+        session_id: Option<String>,
+    },
+    /// `mining.authorize` with `[worker, password]`.
+    Authorize {
+        worker: String,
+        password: Option<String>,
+    },
+    Submit(Submit),
+    /// `mining.ping`, which is answered with `"pong"`.
+    Ping,
+    Notify(Notify),
+    /// `mining.set_difficulty` with `[difficulty, now]`.
+    SetDifficulty {
+        difficulty: f64,
+        /// Whether the difficulty applies to the running job as well.
+        now: bool,
+    },
+    /// `mining.set_target` with `[target, now]`.
+    SetTarget {
+        target: U256,
+        /// Whether the target applies to the running job as well.
+        now: bool,
+    },
+    /// `client.reconnect` with `[host, port, wait]`, all optional. The host and the port default to the current ones.
+    Reconnect {
+        host: Option<String>,
+        port: Option<u16>,
+        /// How long to wait before reconnecting.
+        wait: Duration,
+    },
+    /// `client.show_message` with `[message]`, which is meant for the person running the miner.
+    ShowMessage(String),
+    /// `client.get_version`, which is answered with the name and the version of the miner.
+    GetVersion,
+    /// A method this crate does not know, which only lenient parsing accepts.
+    Unknown {
+        name: String,
+        params: JsonValue,
+    },
+}
+
+/// The parameters of `mining.notify`.
+///
+/// They are either `[job_id, hash, target, clean_jobs, ...]` or, from pools without job ids, `[hash, target]`.
+#[derive(Debug, PartialEq)]
+pub struct Notify {
+    pub job_id: Option<String>,
+    pub hash: H256,
+    pub target: U256,
+    /// Whether the previous jobs are no longer valid.
+    pub clean_jobs: bool,
+    /// The fields after `clean_jobs`, which the miner does not use.
+    pub extra: Vec<JsonValue>,
+}
+
+/// The parameters of `mining.submit`.
+///
+/// They are `[worker, job_id, hash, seal, extranonce2]` or, for jobs without an id, `[hash, seal, extranonce2]`.
+#[derive(Debug, PartialEq)]
+pub struct Submit {
+    pub worker: Option<String>,
+    pub job_id: Option<String>,
+    pub hash: H256,
+    pub seal: Vec<Vec<u8>>,
+    pub extranonce2: Option<Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    /// Some pools answer requests they cannot parse with a null id.
+    pub id: Option<Id>,
+    pub result: StdResult<JsonValue, RpcError>,
+}
+
+/// The error of a response.
+#[derive(Debug, PartialEq)]
+pub struct RpcError {
+    pub code: Option<i64>,
+    pub message: String,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "{} (code {})", self.message, code),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+/// The fields of any message, before its method and parameters are checked.
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(default)]
+    id: Option<Id>,
+    #[serde(default)]
+    method: Option<String>,
+    #[serde(default)]
+    params: Option<JsonValue>,
+    #[serde(default)]
+    result: JsonValue,
+    #[serde(default)]
+    error: JsonValue,
+    /// `jsonrpc` and anything else.
+    #[serde(flatten)]
+    rest: Map<String, JsonValue>,
+}
+
+impl Message {
+    pub fn from_value(value: &JsonValue, strictness: Strictness) -> StdResult<Self, Error> {
+        let envelope: Envelope = serde_json::from_value(value.clone())
+            .map_err(|e| Error::new_protocol(format!("Invalid message {}: {}", value, e)))?;
+        if strictness == Strictness::Strict {
+            if let Some(field) = envelope.rest.keys().find(|field| *field != "jsonrpc") {
+                return Err(Error::new_protocol(format!("Unknown field {} in {}", field, value)))
+            }
+        }
+
+        let name = match envelope.method {
+            Some(name) => name,
+            None => {
+                if envelope.id.is_none() && strictness == Strictness::Strict {
+                    return Err(Error::new_protocol(format!("A response without an id: {}", value)))
+                }
+                let result = if envelope.error.is_null() {
+                    Ok(envelope.result)
+                } else {
+                    let (code, message) = rpc_error(&envelope.error);
+                    Err(RpcError {
+                        code,
+                        message,
+                    })
+                };
+                return Ok(Message::Response(Response {
+                    id: envelope.id,
+                    result,
+                }))
+            }
+        };
+        let params = match envelope.params {
+            None | Some(JsonValue::Null) => Vec::new(),
+            Some(JsonValue::Array(params)) => params,
+            Some(_) if strictness == Strictness::Lenient => Vec::new(),
+            Some(params) => return Err(Error::new_protocol(format!("The parameters of {} are {}", name, params))),
+        };
+        let method = Method::from_params(name, params, strictness)?;
+        Ok(match envelope.id {
+            Some(id) => Message::Request {
+                id,
+                method,
+            },
+            None => Message::Notification(method),
+        })
+    }
+}
+
+impl Method {
+    fn from_params(name: String, params: Vec<JsonValue>, strictness: Strictness) -> StdResult<Self, Error> {
+        let method = match name.as_str() {
+            "mining.subscribe" => {
+                let (user_agent, session_id) = parse_params(&name, &params, 2, strictness)?;
+                Method::Subscribe {
+                    user_agent,
+                    session_id,
+                }
+            }
+            "mining.authorize" => {
+                let (worker, password) = parse_params(&name, &params, 2, strictness)?;
+                Method::Authorize {
+                    worker,
+                    password,
+                }
+            }
+            "mining.submit" => Method::Submit(Submit::from_params(&name, &params, strictness)?),
+            "mining.ping" => {
+                parse_params::<Vec<JsonValue>>(&name, &params, 0, strictness)?;
+                Method::Ping
+            }
+            "mining.notify" => {
+                if strictness == Strictness::Strict && params.len() > 2 && !params[0].is_string() {
+                    return Err(Error::new_protocol(format!("Invalid job id {}", params[0])))
+                }
+                let notify = Notify::from_params(&JsonValue::Array(params))
+                    .map_err(|e| Error::new_protocol(format!("Invalid parameters of {}: {}", name, e)))?;
+                Method::Notify(notify)
+            }
+            "mining.set_difficulty" => {
+                let (difficulty, now): (f64, Option<bool>) = parse_params(&name, &params, 2, strictness)?;
+                Method::SetDifficulty {
+                    difficulty,
+                    now: now.unwrap_or(false),
+                }
+            }
+            "mining.set_target" => {
+                let (target, now): (String, Option<bool>) = parse_params(&name, &params, 2, strictness)?;
+                let target = U256::from_str(clean_0x(&target))
+                    .ok()
+                    .filter(|target| !target.is_zero())
+                    .ok_or_else(|| Error::new_protocol(format!("Invalid target {}", target)))?;
+                Method::SetTarget {
+                    target,
+                    now: now.unwrap_or(false),
+                }
+            }
+            "client.reconnect" => {
+                let (host, port, wait): (Option<String>, Option<JsonValue>, Option<JsonValue>) =
+                    parse_params(&name, &params, 3, strictness)?;
+                let port = match number(port) {
+                    Some(Ok(port)) if port > 0 && port <= u64::from(u16::MAX) => Some(port as u16),
+                    None => None,
+                    _ => return Err(Error::new_protocol(format!("Invalid port in {:?}", params))),
+                };
+                let wait = match number(wait) {
+                    Some(Ok(wait)) => Duration::from_secs(wait),
+                    None => Duration::from_secs(0),
+                    Some(Err(_)) => return Err(Error::new_protocol(format!("Invalid wait in {:?}", params))),
+                };
+                Method::Reconnect {
+                    host: host.filter(|host| !host.is_empty()),
+                    port,
+                    wait,
+                }
+            }
+            "client.show_message" => {
+                let (message,): (String,) = parse_params(&name, &params, 1, strictness)?;
+                Method::ShowMessage(message)
+            }
+            "client.get_version" => {
+                parse_params::<Vec<JsonValue>>(&name, &params, 0, strictness)?;
+                Method::GetVersion
+            }
+            _ if strictness == Strictness::Strict => {
+                return Err(Error::new_protocol(format!("Unknown method {}", name)))
+            }
+            _ => Method::Unknown {
+                name,
+                params: JsonValue::Array(params),
+            },
+        };
+        Ok(method)
+    }
+
+    pub fn name(&self) -> &str {
+        match *self {
+            Method::Subscribe {
+                ..
+            } => "mining.subscribe",
+            Method::Authorize {
+                ..
+            } => "mining.authorize",
+            Method::Submit(_) => "mining.submit",
+            Method::Ping => "mining.ping",
+            Method::Notify(_) => "mining.notify",
+            Method::SetDifficulty {
+                ..
+            } => "mining.set_difficulty",
+            Method::SetTarget {
+                ..
+            } => "mining.set_target",
+            Method::Reconnect {
+                ..
+            } => "client.reconnect",
+            Method::ShowMessage(_) => "client.show_message",
+            Method::GetVersion => "client.get_version",
+            Method::Unknown {
+                ref name,
+                ..
+            } => name,
+        }
+    }
+}
+
+impl Notify {
+    pub fn from_params(params: &JsonValue) -> StdResult<Self, String> {
+        let params = params.as_array().ok_or("The parameters are not an array")?;
+        let (job_id, fields) = match params.len() {
+            0 | 1 => return Err("Too few parameters".to_string()),
+            2 => (None, &params[..]),
+            _ => {
+                let job_id = match params[0] {
+                    JsonValue::String(ref job_id) => job_id.clone(),
+                    JsonValue::Number(ref job_id) => job_id.to_string(),
+                    _ => return Err("Invalid job id".to_string()),
+                };
+                (Some(job_id), &params[1..])
+            }
+        };
+        let hash = fields[0].as_str().and_then(|hash| H256::from_str(clean_0x(hash)).ok()).ok_or("Invalid hash")?;
+        let target =
+            fields[1].as_str().and_then(|target| U256::from_str(clean_0x(target)).ok()).ok_or("Invalid target")?;
+        let clean_jobs = match fields.get(2) {
+            Some(clean_jobs) => clean_jobs.as_bool().ok_or("Invalid clean_jobs")?,
+            None => true,
+        };
+        Ok(Self {
+            job_id,
+            hash,
+            target,
+            clean_jobs,
+            extra: fields.iter().skip(3).cloned().collect(),
+        })
+    }
+}
+
+impl Submit {
+    fn from_params(name: &str, params: &[JsonValue], strictness: Strictness) -> StdResult<Self, Error> {
+        // The seal is the second parameter of the short form, and the fourth of the long one.
+        let (worker, job_id, hash, seal, extranonce2) = if params.get(1).map_or(false, JsonValue::is_array) {
+            let (hash, seal, extranonce2): (String, Vec<String>, Option<String>) =
+                parse_params(name, params, 3, strictness)?;
+            (None, None, hash, seal, extranonce2)
+        } else {
+            let (worker, job_id, hash, seal, extranonce2): (String, String, String, Vec<String>, Option<String>) =
+                parse_params(name, params, 5, strictness)?;
+            (Some(worker), Some(job_id), hash, seal, extranonce2)
+        };
+
+        let invalid = |field: &str, value: &str| Error::new_protocol(format!("Invalid {} {}", field, value));
+        let hash = H256::from_str(clean_0x(&hash)).map_err(|_e| invalid("hash", &hash))?;
+        let seal = seal
+            .iter()
+            .map(|bytes| clean_0x(bytes).from_hex().map_err(|_e| invalid("seal", bytes)))
+            .collect::<StdResult<_, _>>()?;
+        let extranonce2 = match extranonce2 {
+            Some(extranonce2) => Some(extranonce2.from_hex().map_err(|_e| invalid("extranonce2", &extranonce2))?),
+            None => None,
+        };
+        Ok(Self {
+            worker,
+            job_id,
+            hash,
+            seal,
+            extranonce2,
+        })
+    }
+}
+
+/// Reads a parameter that is either a number or a string of one, as pools send both.
+fn number(value: Option<JsonValue>) -> Option<StdResult<u64, ()>> {
+    match value {
+        None | Some(JsonValue::Null) => None,
+        Some(JsonValue::Number(number)) => Some(number.as_u64().ok_or(())),
+        Some(JsonValue::String(string)) => Some(string.parse().map_err(|_e| ())),
+        Some(_) => Some(Err(())),
+    }
+}
+
+/// Deserializes the first `len` parameters, the missing ones being null.
+///
+/// More than `len` parameters are an error only in strict parsing.
+fn parse_params<T: DeserializeOwned>(
+    name: &str,
+    params: &[JsonValue],
+    len: usize,
+    strictness: Strictness,
+) -> StdResult<T, Error> {
+    if params.len() > len && strictness == Strictness::Strict {
+        return Err(Error::new_protocol(format!("{} takes at most {} parameters", name, len)))
+    }
+    let mut params: Vec<_> = params.iter().take(len).cloned().collect();
+    params.resize(len, JsonValue::Null);
+    serde_json::from_value(JsonValue::Array(params))
+        .map_err(|e| Error::new_protocol(format!("Invalid parameters of {}: {}", name, e)))
+}
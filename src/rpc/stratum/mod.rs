@@ -14,12 +14,27 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+mod backoff;
 mod client;
+mod codec;
+mod connect;
 mod dispatch;
 mod error;
+mod layer;
+mod message;
+mod proxy;
 mod runner;
+mod server;
+mod stats;
+mod subscription;
+mod tls;
 
-pub use self::client::Client;
-pub use self::dispatch::{dispatch_fn, Result};
-pub use self::error::Error;
-pub use self::runner::{Config, Runner};
+pub use self::client::{Builder as ClientBuilder, Client, Event, Handle, Pool as ClientPool};
+pub use self::dispatch::{dispatch_fn, Dispatch, NewDispatch, Result};
+pub use self::error::{Error, Kind};
+pub use self::layer::{Layer, Layered, Log, LogLayer, Measured, Metrics, MetricsLayer, Timeout, TimeoutLayer};
+pub use self::message::{Id, Message, Method, Notify, Response, RpcError, Strictness, Submit};
+pub use self::proxy::{Config as ProxyConfig, Runner as ProxyRunner};
+pub use self::runner::{Config, Identity, Pool, Runner};
+pub use self::server::{Config as ServerConfig, CredentialStore, Runner as ServerRunner, Verifier};
+pub use self::tls::Tls;
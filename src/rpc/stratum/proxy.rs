@@ -0,0 +1,336 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+
+use futures::sync::mpsc;
+use futures::{future, Future, Stream};
+use serde_json::Value as JsonValue;
+
+use super::super::super::control::Control;
+use super::super::super::worker::Worker;
+use super::super::{control, RpcRunner};
+use super::client::{Event, SharedWorker, Tx};
+use super::message::{Id, Message, Method, Notify, Response, Strictness};
+use super::runner::{self, submit, summarize, Config as UpstreamConfig};
+use super::server::{Backend, CredentialStore, Downstream, Reply, Share, OTHER_CODE, STALE_CODE};
+use super::stats::Stats;
+use super::subscription::{SharedSubscription, Subscription};
+use super::{dispatch_fn, LogLayer, NewDispatch, Result};
+
+/// The extranonce2 size for the miners when the pool does not assign extranonces.
+const EXTRANONCE2_SIZE: usize = 2;
+const NOT_SUBSCRIBED_CODE: i64 = 25;
+
+#[derive(Clone)]
+pub struct Config {
+    /// The pools to keep a single session with. Every share is submitted under its account.
+    pub upstream: UpstreamConfig,
+    /// Where the miners connect.
+    pub listen: SocketAddr,
+    pub credentials: Arc<CredentialStore>,
+    /// The longest message a miner may send, in bytes.
+    pub max_frame_size: usize,
+    /// How closely the messages of the miners have to follow the protocol.
+    pub strictness: Strictness,
+}
+
+/// Shares a single pool session between the miners connected to it.
+///
+/// Each miner gets the extranonce1 of the pool followed by a byte of its own, so that the miners search different
+/// nonces and their shares can be submitted with the extranonce2 the pool expects.
+pub struct Runner {
+    config: Config,
+}
+
+impl Runner {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+}
+
+impl RpcRunner for Runner {
+    fn run(&self, _recruiter: Arc<Fn() -> Box<Worker> + Send + Sync>, control: Arc<Control>) {
+        let config = &self.config;
+        let pools = match runner::client_pools(&config.upstream) {
+            Ok(pools) => pools,
+            Err(err) => {
+                error!("Invalid stratum pool {}", err);
+                return
+            }
+        };
+        let control_server = config.upstream.control_port.map(|port| control::serve(port, Arc::clone(&control)));
+        let (events_tx, events_rx) = mpsc::unbounded();
+        let builder = runner::bind(&config.upstream, pools).reconnect_on(control.on_reconnect()).events_to(events_tx);
+        let strictness = config.upstream.strictness;
+        let proxy = Arc::new(Proxy {
+            subscription: builder.subscription(),
+            worker: builder.worker(),
+            stats: builder.stats(),
+            tx: builder.sender(),
+            state: Default::default(),
+            slots: Default::default(),
+            pending: Default::default(),
+        });
+        let downstream = Arc::new(Downstream::new(
+            Arc::clone(&config.credentials),
+            Arc::clone(&proxy) as Arc<Backend>,
+            config.max_frame_size,
+            config.strictness,
+        ));
+        let serve = match Arc::clone(&downstream).serve(&config.listen) {
+            Ok(serve) => serve,
+            Err(err) => {
+                error!("Failed to listen on {}: {}", config.listen, err);
+                return
+            }
+        };
+        let summary = summarize(Arc::clone(&proxy.stats), Arc::clone(&proxy.worker), config.upstream.summary_interval);
+        let events = {
+            let proxy = Arc::clone(&proxy);
+            events_rx.for_each(move |event| {
+                if event == Event::Disconnected {
+                    proxy.disconnected();
+                }
+                Ok(())
+            })
+        };
+
+        let new_dispatch = move || {
+            let proxy = Arc::clone(&proxy);
+            let downstream = Arc::clone(&downstream);
+            dispatch_fn(move |req| -> Result {
+                match Message::from_value(&req, strictness) {
+                    Ok(Message::Request {
+                        method,
+                        ..
+                    })
+                    | Ok(Message::Notification(method)) => match method {
+                        Method::Notify(notify) => proxy.notify(notify, &downstream),
+                        method @ Method::SetDifficulty {
+                            ..
+                        }
+                        | method @ Method::SetTarget {
+                            ..
+                        } => proxy.set_share_target(&method, &req["params"], &downstream),
+                        method => warn!("Unsupported method {}", method.name()),
+                    },
+                    Ok(Message::Response(response)) => proxy.answered(&response),
+                    Err(err) => warn!("Invalid message from the pool: {}", err),
+                }
+                Box::new(future::ok(None))
+            })
+        };
+        let client = builder.serve(new_dispatch.wrap(LogLayer)).map_err(|e| error!("stratum client error: {}", e));
+
+        ::tokio::run(future::lazy(move || {
+            if let Some(control_server) = control_server {
+                ::tokio::spawn(control_server);
+            }
+            ::tokio::spawn(summary);
+            ::tokio::spawn(serve);
+            ::tokio::spawn(events);
+            client
+        }));
+    }
+}
+
+struct Proxy {
+    subscription: SharedSubscription,
+    worker: SharedWorker,
+    stats: Arc<Stats>,
+    tx: Tx,
+    state: Mutex<State>,
+    /// The extranonce byte of each session.
+    slots: Mutex<HashMap<usize, Slot>>,
+    /// The sessions waiting for the pool to answer their shares, by the id of the `mining.submit`.
+    pending: Mutex<HashMap<usize, (usize, Reply)>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// The jobs of the pool by the id the miners know them by.
+    jobs: HashMap<String, Notify>,
+    current: Option<String>,
+    next_job_id: usize,
+    /// The last `mining.set_difficulty` or `mining.set_target` of the pool.
+    share_target: Option<JsonValue>,
+}
+
+/// The part of the pool's extranonce a session has.
+struct Slot {
+    byte: u8,
+    /// The pool subscription the extranonce of the session is based on.
+    subscription: Option<Subscription>,
+}
+
+impl Proxy {
+    fn notify(&self, notify: Notify, downstream: &Downstream) {
+        let subscription = self.subscription.read().unwrap().clone();
+        for (session_id, slot) in self.slots.lock().unwrap().iter() {
+            if slot.subscription != subscription {
+                info!("The extranonce of session {:x} is out of date. Reconnecting it", session_id);
+                downstream.disconnect(*session_id);
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        // Jobs without an id get one of the proxy's own.
+        let id = notify.job_id.clone().unwrap_or_else(|| {
+            state.next_job_id += 1;
+            format!("proxy-{:x}", state.next_job_id)
+        });
+        if notify.clean_jobs {
+            state.jobs.clear();
+        }
+        info!("New job {} with hash {}, target: {}", id, notify.hash, notify.target);
+        downstream.broadcast(&notification(&id, &notify, notify.clean_jobs));
+        state.jobs.insert(id.clone(), notify);
+        state.current = Some(id);
+    }
+
+    /// Passes `mining.set_difficulty` or `mining.set_target` on to the miners with the parameters of the pool.
+    fn set_share_target(&self, method: &Method, params: &JsonValue, downstream: &Downstream) {
+        if let Method::SetDifficulty {
+            difficulty,
+            ..
+        } = *method
+        {
+            self.stats.set_difficulty(difficulty);
+        }
+        let message = json!({
+            "id": null,
+            "method": method.name(),
+            "params": params,
+        });
+        downstream.broadcast(&message);
+        self.state.lock().unwrap().share_target = Some(message);
+    }
+
+    /// Passes the answer of the pool to the miner who submitted the share.
+    fn answered(&self, response: &Response) {
+        if !self.stats.answered(response) {
+            debug!("Unexpected response: {:?}", response);
+            return
+        }
+        let id = response.id.as_ref().and_then(Id::as_number);
+        let reply = match id.and_then(|id| self.pending.lock().unwrap().remove(&(id as usize))) {
+            Some((_session_id, reply)) => reply,
+            None => return,
+        };
+        match response.result {
+            Ok(JsonValue::Bool(true)) => reply.send(Ok(json!(true))),
+            Ok(ref result) => reply.send(Err((OTHER_CODE, format!("The pool answered {}", result)))),
+            Err(ref error) => reply.send(Err((error.code.unwrap_or(OTHER_CODE), error.message.clone()))),
+        }
+    }
+
+    /// Fails the shares waiting for an answer, since the pool will not answer them on a new connection.
+    fn disconnected(&self) {
+        for (_id, (_session_id, reply)) in self.pending.lock().unwrap().drain() {
+            reply.send(Err((OTHER_CODE, "The connection to the pool was lost".to_string())));
+        }
+    }
+}
+
+impl Backend for Proxy {
+    fn subscribe(&self, session_id: usize) -> StdResult<(Vec<u8>, usize), (i64, String)> {
+        let subscription = self.subscription.read().unwrap().clone();
+        let extranonce = match subscription {
+            Some(ref subscription) if subscription.extranonce2_size < 2 => {
+                return Err((OTHER_CODE, "The pool leaves no extranonce to share".to_string()))
+            }
+            Some(ref subscription) => (subscription.extranonce1.clone(), subscription.extranonce2_size - 1),
+            None => (Vec::new(), EXTRANONCE2_SIZE),
+        };
+
+        let mut slots = self.slots.lock().unwrap();
+        let byte = match slots.get(&session_id) {
+            Some(slot) => slot.byte,
+            None => (0..=255u8)
+                .find(|byte| !slots.values().any(|slot| slot.byte == *byte))
+                .ok_or_else(|| (OTHER_CODE, "Too many miners".to_string()))?,
+        };
+        slots.insert(
+            session_id,
+            Slot {
+                byte,
+                subscription,
+            },
+        );
+        let (mut extranonce1, extranonce2_size) = extranonce;
+        extranonce1.push(byte);
+        Ok((extranonce1, extranonce2_size))
+    }
+
+    fn greeting(&self) -> Vec<JsonValue> {
+        let state = self.state.lock().unwrap();
+        let mut messages: Vec<_> = state.share_target.iter().cloned().collect();
+        if let Some(ref id) = state.current {
+            messages.push(notification(id, &state.jobs[id], true));
+        }
+        messages
+    }
+
+    fn submit(&self, share: Share, reply: Reply) {
+        // The pool knows the extranonce2 of the miner with the byte of its session in front.
+        let miner_extranonce2 = share.extranonce2.unwrap_or_default();
+        let extranonce2 = match self.slots.lock().unwrap().get(&share.session_id) {
+            Some(slot) => slot.subscription.as_ref().map(|_| [&[slot.byte][..], &miner_extranonce2[..]].concat()),
+            None => return reply.send(Err((NOT_SUBSCRIBED_CODE, "Not subscribed".to_string()))),
+        };
+        let state = self.state.lock().unwrap();
+        let notify = match state.jobs.get(&share.job_id) {
+            Some(notify) => notify,
+            None => return reply.send(Err((STALE_CODE, "Job not found".to_string()))),
+        };
+        if notify.hash != share.hash {
+            return reply.send(Err((OTHER_CODE, "The hash does not match the job".to_string())))
+        }
+        let worker = self.worker.read().unwrap().clone().unwrap_or_default();
+
+        let id = self.stats.submitted(share.hash);
+        debug!("Forward the share of {} for the job {} as {}", share.worker, share.job_id, id);
+        self.pending.lock().unwrap().insert(id, (share.session_id, reply));
+        let _ = self.tx.unbounded_send(submit(id, &worker, notify, share.seal, extranonce2));
+    }
+
+    fn closed(&self, session_id: usize) {
+        self.slots.lock().unwrap().remove(&session_id);
+        self.pending.lock().unwrap().retain(|_id, &mut (session, _)| session != session_id);
+    }
+}
+
+/// Makes `mining.notify` for the miners, with the id they know the job by.
+fn notification(id: &str, notify: &Notify, clean_jobs: bool) -> JsonValue {
+    let mut params = vec![
+        json!(id),
+        json!(format!("0x{:x}", notify.hash)),
+        json!(format!("0x{:x}", notify.target)),
+        json!(clean_jobs),
+    ];
+    params.extend(notify.extra.iter().cloned());
+    json!({
+        "id": null,
+        "method": "mining.notify",
+        "params": params,
+    })
+}
@@ -14,113 +14,460 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::str::FromStr;
+use std::path::PathBuf;
+use std::result::Result as StdResult;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::spawn;
+use std::time::Duration;
 
-use ethereum_types::{clean_0x, H256, U256};
-use futures::{future, Future};
+use ethereum_types::U256;
+use futures::sync::mpsc;
+use futures::{future, Future, Stream};
 use rustc_hex::ToHex;
 use serde_json::Value as JsonValue;
+use tokio::timer::Interval;
 
-use super::super::super::worker::{work, Worker};
-use super::super::RpcRunner;
-use super::client::Client;
-use super::{dispatch_fn, Result};
+use super::super::super::control::Control;
+use super::super::super::worker::{work, Lane, Worker};
+use super::super::{control, RpcRunner};
+use super::client::{
+    Builder, Client, Event, Pool as ClientPool, SharedWorker, Tx, CONNECT_TIMEOUT_SECS, MAX_FRAME_SIZE,
+};
+use super::connect::parse_addr;
+use super::message::{Message, Method, Notify, Strictness};
+use super::stats::Stats;
+use super::subscription::SharedSubscription;
+use super::tls::Tls;
+use super::{dispatch_fn, LogLayer, NewDispatch, Result};
 
-#[derive(Deserialize)]
-pub struct RpcError {
-    pub code: i32,
-    pub message: String,
-}
+const PRIMARY_CHECK_INTERVAL_SECS: u64 = 60;
+const SUMMARY_INTERVAL_SECS: u64 = 60;
 
-#[derive(Deserialize)]
-pub struct Rpc {
-    pub id: usize,
-    pub method: Option<String>,
-    pub error: Option<RpcError>,
+/// A stratum pool and the credentials for it.
+#[derive(Clone)]
+pub struct Pool {
+    /// `host:port`, `stratum+tcp://host:port` or `stratum+ssl://host:port` of the pool.
+    pub url: String,
+    pub id: String,
+    pub pwd: String,
+    /// PEM file with additional CA certificates to trust for `stratum+ssl://`.
+    pub ca_bundle: Option<PathBuf>,
+    /// Hex SHA-256 fingerprint of the certificate a `stratum+ssl://` pool must present.
+    pub pinned_cert: Option<String>,
 }
 
+/// A worker that mines under credentials of its own instead of those of the pools.
 #[derive(Clone)]
-pub struct Config {
+pub struct Identity {
     pub id: String,
     pub pwd: String,
-    pub port: u16,
+    /// The threads of each job, instead of an even share of the threads of the miner.
+    pub threads: Option<usize>,
+}
+
+#[derive(Clone)]
+pub struct Config {
+    /// Pools in order of preference. The first one is the primary.
+    pub pools: Vec<Pool>,
+    /// How many times connecting to a pool may fail in a row before moving to the next one.
+    pub max_connect_failures: usize,
+    /// How often to check whether the primary pool is back while another one is in use.
+    pub primary_check_interval: Duration,
+    /// How long connecting to each address of a pool may take.
+    pub connect_timeout: Duration,
+    /// How often to log the shares of the session.
+    pub summary_interval: Duration,
+    /// The longest message the pool may send, in bytes.
+    pub max_frame_size: usize,
+    /// Reconnects when the pool sends nothing but answers, such as those to pings and shares, for this long, if set.
+    pub idle_timeout: Option<Duration>,
+    /// How often to ping the pool, if at all.
+    pub ping_interval: Option<Duration>,
+    /// How closely the messages of the pool have to follow the protocol.
+    pub strictness: Strictness,
+    /// Serves the control API on 127.0.0.1:port if set.
+    pub control_port: Option<u16>,
+    /// Workers that each connect to the pools on their own, with their own jobs, threads and shares.
+    /// If empty, a single connection uses the credentials of the pools. The proxy always does so.
+    pub workers: Vec<Identity>,
+}
+
+impl Config {
+    /// A config for `pools` with the default settings, which mines with the credentials of the pools.
+    pub fn new(pools: Vec<Pool>) -> Self {
+        Self {
+            pools,
+            max_connect_failures: 1,
+            primary_check_interval: Duration::from_secs(PRIMARY_CHECK_INTERVAL_SECS),
+            connect_timeout: Duration::from_secs(CONNECT_TIMEOUT_SECS),
+            summary_interval: Duration::from_secs(SUMMARY_INTERVAL_SECS),
+            max_frame_size: MAX_FRAME_SIZE,
+            idle_timeout: None,
+            ping_interval: None,
+            strictness: Strictness::Lenient,
+            control_port: None,
+            workers: Vec::new(),
+        }
+    }
+
+    /// Splits the config into one for each worker, with the credentials of the worker in every pool.
+    fn split(&self) -> Vec<(Config, Option<usize>)> {
+        if self.workers.is_empty() {
+            return vec![(self.clone(), None)]
+        }
+        self.workers
+            .iter()
+            .map(|identity| {
+                let mut config = self.clone();
+                for pool in &mut config.pools {
+                    pool.id = identity.id.clone();
+                    pool.pwd = identity.pwd.clone();
+                }
+                (config, identity.threads)
+            })
+            .collect()
+    }
 }
 
 pub struct Runner {
-    id: String,
-    pwd: String,
-    port: u16,
+    config: Config,
 }
 
 impl Runner {
     pub fn new(config: &Config) -> Self {
         Self {
-            id: config.id.clone(),
-            pwd: config.pwd.clone(),
-            port: config.port,
+            config: config.clone(),
         }
     }
 }
 
+/// Parses the pools of `config` for the client. Fails on an invalid URL or invalid TLS settings.
+pub fn client_pools(config: &Config) -> StdResult<Vec<ClientPool>, String> {
+    config
+        .pools
+        .iter()
+        .map(|pool| {
+            let (addr, tls) = parse_addr(&pool.url)?;
+            let tls = if tls {
+                let ca_bundle = pool.ca_bundle.as_ref().map(PathBuf::as_path);
+                let tls = Tls::new(&addr, ca_bundle, pool.pinned_cert.as_ref().map(String::as_str))
+                    .map_err(|err| format!("{}: {}", pool.url, err))?;
+                Some(Arc::new(tls))
+            } else {
+                None
+            };
+            Ok(ClientPool {
+                addr,
+                id: pool.id.to_owned(),
+                pwd: pool.pwd.to_owned(),
+                tls,
+            })
+        })
+        .collect()
+}
+
+/// Makes a client for `pools`, which are those of `config`.
+pub fn bind(config: &Config, pools: Vec<ClientPool>) -> Builder {
+    let mut builder = Client::bind(pools)
+        .failover(config.max_connect_failures, config.primary_check_interval)
+        .connect_timeout(config.connect_timeout)
+        .max_frame_size(config.max_frame_size)
+        .strictness(config.strictness);
+    if let Some(idle_timeout) = config.idle_timeout {
+        builder = builder.idle_timeout(idle_timeout);
+    }
+    if let Some(ping_interval) = config.ping_interval {
+        builder = builder.ping_interval(ping_interval);
+    }
+    builder
+}
+
 impl RpcRunner for Runner {
-    fn run(&self, recruiter: Arc<Fn() -> Box<Worker> + Send + Sync>, jobs: usize) {
-        let job_id = Arc::new(AtomicUsize::new(1));
-        let addr = ([127, 0, 0, 1], self.port).into();
-        let client = Client::bind(&addr, self.id.to_owned(), self.pwd.to_owned())
-            .serve(move || {
-                let job_id = job_id.clone();
-                let recruiter = Arc::clone(&recruiter);
-                dispatch_fn(move |req| -> Result {
-                    let vec = ::serde_json::to_vec(&req).unwrap();
-                    let rpc: Rpc = ::serde_json::from_slice(&vec).unwrap();
-                    if rpc.method.is_some() {
-                        match rpc.method.unwrap().as_ref() {
-                            "mining.notify" => {
-                                let worker = recruiter();
-                                let id = job_id.fetch_add(1, Ordering::SeqCst);
-                                return Box::new(future::ok(get_work(worker, jobs, id, req)))
-                            }
-                            _ => warn!("Unsupported method"),
-                        }
-                    }
+    fn run(&self, recruiter: Arc<Fn() -> Box<Worker> + Send + Sync>, control: Arc<Control>) {
+        let mut sessions = Vec::new();
+        for (config, threads) in self.config.split() {
+            match client_pools(&config) {
+                Ok(pools) => sessions.push((config, pools, threads)),
+                Err(err) => {
+                    error!("Invalid stratum pool {}", err);
+                    return
+                }
+            }
+        }
+        let control_server = self.config.control_port.map(|port| control::serve(port, Arc::clone(&control)));
+        let share = sessions.len();
 
-                    if rpc.error.is_some() {
-                        let error = rpc.error.unwrap();
-                        warn!("{} {}", error.code, error.message);
-                    }
-                    Box::new(future::ok(None))
+        ::tokio::run(future::lazy(move || {
+            if let Some(control_server) = control_server {
+                ::tokio::spawn(control_server);
+            }
+            for (config, pools, threads) in sessions {
+                let lane = Arc::new(Lane::new(threads, share));
+                ::tokio::spawn(session(&config, pools, lane, Arc::clone(&recruiter), Arc::clone(&control)));
+            }
+            Ok(())
+        }));
+    }
+}
+
+/// Makes the client of a connection to `pools`, those of `config`, which mines the jobs of `lane`.
+///
+/// The summary of the connection is spawned along with it, so this should be called within the runtime.
+fn session(
+    config: &Config,
+    pools: Vec<ClientPool>,
+    lane: Arc<Lane>,
+    recruiter: Arc<Fn() -> Box<Worker> + Send + Sync>,
+    control: Arc<Control>,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    let job_id = Arc::new(AtomicUsize::new(1));
+    let strictness = config.strictness;
+    let reconnect = control.on_reconnect();
+    let (events_tx, events_rx) = mpsc::unbounded();
+    let builder = bind(config, pools).reconnect_on(reconnect).events_to(events_tx);
+    let miner = Miner {
+        recruiter,
+        control,
+        lane,
+        subscription: builder.subscription(),
+        worker: builder.worker(),
+        share_target: Default::default(),
+        cleaned: Default::default(),
+        stats: builder.stats(),
+        tx: builder.sender(),
+    };
+    ::tokio::spawn(summarize(Arc::clone(&miner.stats), Arc::clone(&miner.worker), config.summary_interval));
+    let share_target = Arc::clone(&miner.share_target);
+    ::tokio::spawn(events_rx.for_each(move |event| {
+        // The target of the lost pool does not apply to the jobs of the next one.
+        if event == Event::Disconnected {
+            share_target.reset();
+        }
+        Ok(())
+    }));
+    let new_dispatch = move || {
+        let job_id = job_id.clone();
+        let miner = miner.clone();
+        dispatch_fn(move |req| -> Result {
+            let method = match Message::from_value(&req, strictness) {
+                Ok(Message::Request {
+                    method,
+                    ..
                 })
+                | Ok(Message::Notification(method)) => method,
+                Ok(Message::Response(response)) => {
+                    if !miner.stats.answered(&response) {
+                        debug!("Unexpected response: {}", req);
+                    }
+                    return Box::new(future::ok(None))
+                }
+                Err(err) => {
+                    warn!("Invalid message from the pool: {}", err);
+                    return Box::new(future::ok(None))
+                }
+            };
+            match method {
+                Method::Notify(notify) => miner.get_work(job_id.fetch_add(1, Ordering::SeqCst), notify),
+                Method::SetDifficulty {
+                    difficulty,
+                    now,
+                } => match difficulty_to_target(difficulty) {
+                    Some(target) => set_share_target(&miner.share_target, &miner.stats, target, difficulty, now),
+                    None => warn!("Invalid difficulty {}", difficulty),
+                },
+                Method::SetTarget {
+                    target,
+                    now,
+                } => {
+                    let difficulty = target_to_difficulty(&target);
+                    set_share_target(&miner.share_target, &miner.stats, target, difficulty, now)
+                }
+                method => warn!("Unsupported method {}", method.name()),
+            }
+            Box::new(future::ok(None))
+        })
+    };
+    let client = builder.serve(new_dispatch.wrap(LogLayer)).map_err(|e| error!("stratum client error: {}", e));
+    Box::new(client)
+}
+
+/// Logs the shares of the session every `interval`, along with the worker they are submitted as.
+pub fn summarize(
+    stats: Arc<Stats>,
+    worker: SharedWorker,
+    interval: Duration,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    Box::new(
+        Interval::new_interval(interval)
+            .for_each(move |_| {
+                match *worker.read().unwrap() {
+                    Some(ref worker) => info!("Shares of {}: {}", worker, stats),
+                    None => info!("Shares: {}", stats),
+                }
+                Ok(())
             })
-            .map_err(|e| error!("stratum client error: {}", e));
+            .map_err(|e| error!("summary timer error: {}", e)),
+    )
+}
+
+/// The share target set by the pool, which replaces the targets of the jobs.
+#[derive(Default)]
+struct ShareTarget {
+    target: RwLock<Option<U256>>,
+    /// The target of the running job.
+    current_job: Mutex<Option<Arc<RwLock<U256>>>>,
+}
+
+impl ShareTarget {
+    /// Sets the target of the next jobs, and of the running one too if `now` is true.
+    fn set(&self, target: U256, now: bool) {
+        *self.target.write().unwrap() = Some(target);
+        if now {
+            if let Some(ref current_job) = *self.current_job.lock().unwrap() {
+                *current_job.write().unwrap() = target;
+            }
+        }
+    }
+
+    /// Forgets the target, so that the next jobs have their own targets until the pool sets another.
+    fn reset(&self) {
+        *self.target.write().unwrap() = None;
+    }
+
+    /// Makes the target of a new job whose own target is `job_target`.
+    fn start(&self, job_target: U256) -> Arc<RwLock<U256>> {
+        let target = Arc::new(RwLock::new(self.target.read().unwrap().unwrap_or(job_target)));
+        *self.current_job.lock().unwrap() = Some(Arc::clone(&target));
+        target
+    }
+}
+
+/// Handles `mining.set_difficulty` and `mining.set_target`.
+///
+/// The new target applies to the next jobs, and to the running job as well if `now` is true.
+fn set_share_target(share_target: &ShareTarget, stats: &Stats, target: U256, difficulty: f64, now: bool) {
+    share_target.set(target, now);
+    stats.set_difficulty(difficulty);
+    if now {
+        info!("The pool changed the share target of the running and next jobs to {} ({})", target, stats);
+    } else {
+        info!("The pool changed the share target of the next jobs to {} ({})", target, stats);
+    }
+}
 
-        ::tokio::run(client);
+/// Fractional difficulties are kept up to this precision.
+const DIFFICULTY_SCALE: u64 = 1_000_000;
+
+pub fn difficulty_to_target(difficulty: f64) -> Option<U256> {
+    if difficulty.is_nan() || difficulty <= 0.0 {
+        return None
+    }
+    if difficulty <= 1.0 {
+        return Some(U256::max_value())
+    }
+    if difficulty.fract() == 0.0 && difficulty < u64::MAX as f64 {
+        return Some(U256::max_value() / U256::from(difficulty as u64))
     }
+    let scaled = (difficulty * DIFFICULTY_SCALE as f64).round() as u64;
+    Some(U256::max_value() / U256::from(scaled) * U256::from(DIFFICULTY_SCALE))
+}
+
+fn target_to_difficulty(target: &U256) -> f64 {
+    let quotient = U256::max_value() / *target;
+    let shift = quotient.bits().saturating_sub(64);
+    (quotient >> shift).low_u64() as f64 * 2f64.powi(shift as i32)
 }
 
-fn get_work(worker: Box<Worker>, jobs: usize, job_id: usize, req: JsonValue) -> Option<JsonValue> {
-    let params = req["params"].clone();
-    if params.is_array() && params.as_array().unwrap().len() == 2 {
-        let hash = H256::from_str(clean_0x(&params[0].as_str().unwrap())).unwrap();
-        let target = U256::from_str(clean_0x(&params[1].as_str().unwrap())).unwrap();
-        if let Some(solution) = work(&hash, &target, worker, jobs) {
-            return Some(submit(job_id, hash, solution))
+/// Mines the jobs the pool notifies.
+#[derive(Clone)]
+struct Miner {
+    recruiter: Arc<Fn() -> Box<Worker> + Send + Sync>,
+    control: Arc<Control>,
+    lane: Arc<Lane>,
+    subscription: SharedSubscription,
+    worker: SharedWorker,
+    share_target: Arc<ShareTarget>,
+    /// The id of the last job that cleaned the previous ones, which are no longer started or submitted.
+    cleaned: Arc<AtomicUsize>,
+    stats: Arc<Stats>,
+    tx: Tx,
+}
+
+impl Miner {
+    /// Starts the job on threads of its own, and submits its solution through the client.
+    ///
+    /// A job with `clean_jobs` stops the running ones. Otherwise, they keep running and submit their solutions.
+    fn get_work(&self, job_id: usize, notify: Notify) {
+        if !notify.extra.is_empty() {
+            debug!("Ignore the extra fields of the job {:?}: {:?}", notify.job_id, notify.extra);
         }
-    };
+        if notify.clean_jobs {
+            self.cleaned.store(job_id, Ordering::SeqCst);
+            self.lane.clean();
+        }
+        let miner = self.clone();
+        spawn(move || miner.mine(job_id, &notify));
+    }
+
+    fn mine(&self, job_id: usize, notify: &Notify) {
+        // A newer job may have cleaned this one before its thread got to start it.
+        if self.cleaned.load(Ordering::SeqCst) > job_id {
+            return
+        }
+        let target = self.share_target.start(notify.target);
+        // Each job gets its own extranonce2, so that jobs for the same hash search different nonces.
+        let subscription = self.subscription.read().unwrap().clone();
+        let (extranonce, extranonce2) = match subscription {
+            Some(ref subscription) => {
+                let extranonce2 = subscription.extranonce2(job_id);
+                ([&subscription.extranonce1[..], &extranonce2[..]].concat(), Some(extranonce2))
+            }
+            None => (Vec::new(), None),
+        };
 
-    None
+        if let Some(solution) = work(&notify.hash, &target, &extranonce, &self.recruiter, &self.control, &self.lane) {
+            if *self.subscription.read().unwrap() != subscription {
+                info!("Drop the solution for the job {:?} of a lost connection", notify.job_id);
+                return
+            }
+            if self.cleaned.load(Ordering::SeqCst) > job_id {
+                info!("Drop the solution for the job {:?}, which a newer job cleaned", notify.job_id);
+                return
+            }
+            let id = self.stats.submitted(notify.hash);
+            let worker = self.worker.read().unwrap().clone().unwrap_or_default();
+            let _ = self.tx.unbounded_send(submit(id, &worker, notify, solution, extranonce2));
+        }
+    }
 }
 
-pub fn submit(job_id: usize, hash: H256, solution: Vec<Vec<u8>>) -> JsonValue {
+/// Makes `mining.submit` for a solution of `notify`.
+///
+/// The parameters are `[worker, job_id, hash, seal, extranonce2]`, or `[hash, seal, extranonce2]` for a job without
+/// an id. `extranonce2` is left out if the pool does not assign extranonces.
+pub fn submit(
+    id: usize,
+    worker: &str,
+    notify: &Notify,
+    solution: Vec<Vec<u8>>,
+    extranonce2: Option<Vec<u8>>,
+) -> JsonValue {
     let seal: Vec<_> = solution.iter().map(|bytes| format!("0x{}", bytes.to_hex())).collect();
+    let mut params = Vec::new();
+    if let Some(ref job_id) = notify.job_id {
+        params.push(json!(worker));
+        params.push(json!(job_id));
+    }
+    params.push(json!(format!("0x{:x}", notify.hash)));
+    params.push(json!(seal));
+    if let Some(extranonce2) = extranonce2 {
+        params.push(json!(extranonce2.to_hex()));
+    }
     json!({
         "jsonrpc": "2.0",
-        "id": job_id,
+        "id": id,
         "method": "mining.submit",
-        "params": [
-            format!("0x{:x}", hash),
-            seal,
-        ],
+        "params": params,
     })
 }
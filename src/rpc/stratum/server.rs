@@ -0,0 +1,535 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+use ethereum_types::{H256, U256};
+use futures::sync::mpsc;
+use futures::{future, Future, Sink, Stream};
+use rustc_hex::ToHex;
+use serde_json::Value as JsonValue;
+use tokio::codec::Framed;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::Interval;
+
+use super::super::super::control::Control;
+use super::super::super::worker::Worker;
+use super::super::http::{self, parse_work};
+use super::super::{Endpoint, RpcRunner, SubmitPolicy};
+use super::codec::Codec;
+use super::error::Error;
+use super::message::{Message, Method, Strictness, Submit};
+use super::runner::difficulty_to_target;
+
+const EXTRANONCE2_SIZE: usize = 2;
+
+pub const OTHER_CODE: i64 = 20;
+pub const STALE_CODE: i64 = 21;
+const DUPLICATE_CODE: i64 = 22;
+const LOW_DIFFICULTY_CODE: i64 = 23;
+const UNAUTHORIZED_CODE: i64 = 24;
+
+/// Decides which workers may mine on the server.
+pub trait CredentialStore: Send + Sync {
+    fn authorize(&self, worker: &str, password: &str) -> bool;
+}
+
+/// Passwords by worker name.
+impl CredentialStore for HashMap<String, String> {
+    fn authorize(&self, worker: &str, password: &str) -> bool {
+        self.get(worker).map_or(false, |expected| expected == password)
+    }
+}
+
+/// Checks the shares miners submit.
+pub trait Verifier: Send + Sync {
+    /// Returns the value `seal` reaches for `hash`, which must not exceed a target, or `None` if the seal is invalid.
+    fn score(&self, hash: &H256, seal: &[Vec<u8>]) -> Option<U256>;
+}
+
+#[derive(Clone)]
+pub struct Config {
+    /// Where the miners connect.
+    pub listen: SocketAddr,
+    /// The node to get work from with `miner_getWork` and to submit block solutions to with `miner_submitWork`.
+    pub node: Endpoint,
+    /// How often to ask the node for work.
+    pub poll_interval: Duration,
+    /// The difficulty of shares. Without it, shares must meet the target of the block.
+    pub share_difficulty: Option<f64>,
+    pub credentials: Arc<CredentialStore>,
+    pub verifier: Arc<Verifier>,
+    /// The longest message a miner may send, in bytes.
+    pub max_frame_size: usize,
+    /// How closely the messages of the miners have to follow the protocol.
+    pub strictness: Strictness,
+}
+
+/// Distributes the work of a node to stratum miners. The server does not mine by itself.
+pub struct Runner {
+    config: Config,
+    /// The target of the share difficulty, or why the difficulty is invalid.
+    share_target: Result<Option<U256>, String>,
+}
+
+impl Runner {
+    pub fn new(config: &Config) -> Self {
+        let share_target = match config.share_difficulty {
+            Some(difficulty) => match difficulty_to_target(difficulty) {
+                Some(target) => Ok(Some(target)),
+                None => Err(format!("Invalid share difficulty {}", difficulty)),
+            },
+            None => Ok(None),
+        };
+        Self {
+            config: config.clone(),
+            share_target,
+        }
+    }
+}
+
+impl RpcRunner for Runner {
+    fn run(&self, _recruiter: Arc<Fn() -> Box<Worker> + Send + Sync>, _control: Arc<Control>) {
+        let config = &self.config;
+        let share_target = match self.share_target {
+            Ok(share_target) => share_target,
+            Err(ref err) => {
+                error!("{}", err);
+                return
+            }
+        };
+        let node = Arc::new(Node {
+            endpoint: config.node.clone(),
+            share_target,
+            share_difficulty: config.share_difficulty,
+            verifier: Arc::clone(&config.verifier),
+            next_job_id: AtomicUsize::new(1),
+            jobs: Default::default(),
+            extranonce1s: Default::default(),
+        });
+        let downstream = Arc::new(Downstream::new(
+            Arc::clone(&config.credentials),
+            Arc::clone(&node) as Arc<Backend>,
+            config.max_frame_size,
+            config.strictness,
+        ));
+        let serve = match Arc::clone(&downstream).serve(&config.listen) {
+            Ok(serve) => serve,
+            Err(err) => {
+                error!("Failed to listen on {}: {}", config.listen, err);
+                return
+            }
+        };
+
+        let poll = Interval::new(Instant::now(), config.poll_interval)
+            .map_err(|e| error!("poll timer error: {}", e))
+            .for_each(move |_| {
+                let request = json!({
+                    "jsonrpc": "2.0",
+                    "method": "miner_getWork",
+                    "params": [],
+                    "id": 1
+                });
+                let node = Arc::clone(&node);
+                let downstream = Arc::clone(&downstream);
+                http::request(&node.endpoint, request).then(move |result| {
+                    match result {
+                        Ok(res) => match parse_work(&res["result"]) {
+                            Some((hash, target)) => node.update_work(hash, target, &downstream),
+                            None => warn!("Invalid response to miner_getWork: {}", res),
+                        },
+                        Err(err) => warn!("Failed to get work from {}: {}", node.endpoint, err),
+                    }
+                    Ok(())
+                })
+            });
+
+        ::tokio::run(future::lazy(move || {
+            ::tokio::spawn(poll);
+            serve
+        }));
+    }
+}
+
+type Tx = mpsc::UnboundedSender<JsonValue>;
+
+/// The result of a request, or the code and the message of its error.
+pub type RpcResult = Result<JsonValue, (i64, String)>;
+
+/// Where the result of a request from a miner goes.
+pub struct Reply {
+    tx: Tx,
+    id: JsonValue,
+}
+
+impl Reply {
+    pub fn send(self, result: RpcResult) {
+        let response = match result {
+            Ok(result) => json!({"id": self.id, "result": result, "error": null}),
+            Err((code, message)) => json!({"id": self.id, "result": null, "error": [code, message, null]}),
+        };
+        let _ = self.tx.unbounded_send(response);
+    }
+}
+
+/// A share a miner submitted with `[worker, job_id, hash, seal, extranonce2]`.
+pub struct Share {
+    pub session_id: usize,
+    pub worker: String,
+    pub job_id: String,
+    pub hash: H256,
+    pub seal: Vec<Vec<u8>>,
+    pub extranonce2: Option<Vec<u8>>,
+}
+
+/// Where the work of the server comes from and where its shares go.
+pub trait Backend: Send + Sync {
+    /// Returns the extranonce1 and the extranonce2 size of the session `session_id`.
+    fn subscribe(&self, session_id: usize) -> Result<(Vec<u8>, usize), (i64, String)>;
+    /// Returns what a miner gets once authorized, such as the share difficulty and the current job.
+    fn greeting(&self) -> Vec<JsonValue>;
+    /// Checks `share`, and answers it through `reply` now or later.
+    fn submit(&self, share: Share, reply: Reply);
+    fn closed(&self, _session_id: usize) {}
+}
+
+/// Serves stratum miners, who get their work from `backend`.
+pub struct Downstream {
+    credentials: Arc<CredentialStore>,
+    backend: Arc<Backend>,
+    max_frame_size: usize,
+    strictness: Strictness,
+    next_session_id: AtomicUsize,
+    sessions: Mutex<HashMap<usize, Tx>>,
+    /// The authorized sessions, which get the new jobs.
+    miners: Mutex<HashMap<usize, Tx>>,
+}
+
+impl Downstream {
+    pub fn new(
+        credentials: Arc<CredentialStore>,
+        backend: Arc<Backend>,
+        max_frame_size: usize,
+        strictness: Strictness,
+    ) -> Self {
+        Self {
+            credentials,
+            backend,
+            max_frame_size,
+            strictness,
+            next_session_id: AtomicUsize::new(1),
+            sessions: Default::default(),
+            miners: Default::default(),
+        }
+    }
+
+    /// Accepts miners on `listen`.
+    pub fn serve(self: Arc<Self>, listen: &SocketAddr) -> io::Result<Box<Future<Item = (), Error = ()> + Send>> {
+        let listener = TcpListener::bind(listen)?;
+        info!("Stratum server started, listening on {}", listen);
+        Ok(Box::new(listener.incoming().map_err(|e| error!("stratum server error: {}", e)).for_each(move |socket| {
+            Arc::clone(&self).accept(socket);
+            Ok(())
+        })))
+    }
+
+    /// Sends `message` to every authorized miner.
+    pub fn broadcast(&self, message: &JsonValue) {
+        for tx in self.miners.lock().unwrap().values() {
+            let _ = tx.unbounded_send(message.clone());
+        }
+    }
+
+    /// Closes the connection of the session `session_id`, whose miner has to subscribe again.
+    pub fn disconnect(&self, session_id: usize) {
+        self.miners.lock().unwrap().remove(&session_id);
+        if let Some(tx) = self.sessions.lock().unwrap().get(&session_id) {
+            let _ = tx.unbounded_send(JsonValue::Null);
+        }
+    }
+
+    fn accept(self: Arc<Self>, socket: TcpStream) {
+        let id = self.next_session_id.fetch_add(1, Ordering::SeqCst);
+        let peer = socket.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+        info!("A miner connected from {} (session {:x})", peer, id);
+        let (sink, stream) = Framed::new(socket, Codec::new(self.max_frame_size)).split();
+        let (tx, rx) = mpsc::unbounded();
+        self.sessions.lock().unwrap().insert(id, tx.clone());
+
+        let mut session = Session {
+            id,
+            downstream: Arc::clone(&self),
+            tx,
+            workers: HashSet::new(),
+        };
+        let reading = stream.for_each(move |req| {
+            session.handle(&req);
+            Ok(())
+        });
+        // `disconnect` sends null to end the connection.
+        let rx = rx.take_while(|message: &JsonValue| Ok(!message.is_null())).map_err(|()| Error::new_closed());
+        let writing = sink.send_all(rx).map(|_| ());
+        ::tokio::spawn(reading.select(writing).then(move |result| {
+            self.sessions.lock().unwrap().remove(&id);
+            self.miners.lock().unwrap().remove(&id);
+            self.backend.closed(id);
+            match result {
+                Ok(_) => info!("The miner of session {:x} disconnected", id),
+                Err((err, _)) => warn!("The miner of session {:x} disconnected: {}", id, err),
+            }
+            Ok(())
+        }));
+    }
+}
+
+/// A connection from a miner.
+struct Session {
+    id: usize,
+    downstream: Arc<Downstream>,
+    tx: Tx,
+    /// The workers authorized on this connection.
+    workers: HashSet<String>,
+}
+
+impl Session {
+    fn handle(&mut self, req: &JsonValue) {
+        let reply = Reply {
+            tx: self.tx.clone(),
+            id: req["id"].clone(),
+        };
+        let method = match Message::from_value(req, self.downstream.strictness) {
+            Ok(Message::Request {
+                method,
+                ..
+            }) => method,
+            Ok(_) => {
+                debug!("Ignore a message that is not a request: {}", req);
+                return
+            }
+            Err(err) => {
+                warn!("Session {:x} sent an invalid message: {}", self.id, err);
+                if !req["id"].is_null() {
+                    reply.send(Err((OTHER_CODE, err.to_string())));
+                }
+                return
+            }
+        };
+        match method {
+            Method::Subscribe {
+                ..
+            } => reply.send(self.subscribe()),
+            Method::Authorize {
+                worker,
+                password,
+            } => {
+                let was_authorized = !self.workers.is_empty();
+                reply.send(self.authorize(worker, &password.unwrap_or_default()));
+                if !was_authorized && !self.workers.is_empty() {
+                    self.start();
+                }
+            }
+            Method::Submit(submit) => match self.share(submit) {
+                Ok(share) => self.downstream.backend.submit(share, reply),
+                Err(err) => reply.send(Err(err)),
+            },
+            Method::Ping => reply.send(Ok(json!("pong"))),
+            method => reply.send(Err((OTHER_CODE, format!("Unsupported method {}", method.name())))),
+        }
+    }
+
+    fn subscribe(&self) -> RpcResult {
+        let (extranonce1, extranonce2_size) = self.downstream.backend.subscribe(self.id)?;
+        let session_id = format!("{:x}", self.id);
+        Ok(json!([
+            [["mining.set_difficulty", session_id], ["mining.notify", session_id]],
+            extranonce1.to_hex(),
+            extranonce2_size
+        ]))
+    }
+
+    fn authorize(&mut self, worker: String, password: &str) -> RpcResult {
+        if !self.downstream.credentials.authorize(&worker, password) {
+            warn!("Session {:x} failed to authorize the worker {}", self.id, worker);
+            return Err((UNAUTHORIZED_CODE, "Unauthorized worker".to_string()))
+        }
+        info!("Session {:x} authorized the worker {}", self.id, worker);
+        self.workers.insert(worker);
+        Ok(json!(true))
+    }
+
+    /// Sends the greeting of the backend, and the following jobs as they come.
+    fn start(&self) {
+        // A job broadcast in between is sent twice rather than missed.
+        self.downstream.miners.lock().unwrap().insert(self.id, self.tx.clone());
+        for message in self.downstream.backend.greeting() {
+            let _ = self.tx.unbounded_send(message);
+        }
+    }
+
+    fn share(&self, submit: Submit) -> Result<Share, (i64, String)> {
+        let (worker, job_id) = match (submit.worker, submit.job_id) {
+            (Some(worker), Some(job_id)) => (worker, job_id),
+            _ => return Err((OTHER_CODE, "Invalid parameters".to_string())),
+        };
+        if !self.workers.contains(&worker) {
+            return Err((UNAUTHORIZED_CODE, "Unauthorized worker".to_string()))
+        }
+        Ok(Share {
+            session_id: self.id,
+            worker,
+            job_id,
+            hash: submit.hash,
+            seal: submit.seal,
+            extranonce2: submit.extranonce2,
+        })
+    }
+}
+
+/// Gets work from a node and submits block solutions to it.
+struct Node {
+    endpoint: Endpoint,
+    share_target: Option<U256>,
+    share_difficulty: Option<f64>,
+    verifier: Arc<Verifier>,
+    next_job_id: AtomicUsize,
+    jobs: Mutex<Jobs>,
+    /// The extranonce1 of each session, which no other open session has.
+    extranonce1s: Mutex<HashMap<usize, u16>>,
+}
+
+/// The jobs shares are accepted for. They are forgotten when the node starts a new block.
+#[derive(Default)]
+struct Jobs {
+    current: Option<String>,
+    by_id: HashMap<String, Job>,
+}
+
+struct Job {
+    hash: H256,
+    target: U256,
+    /// The seals submitted for this job.
+    seals: HashSet<Vec<Vec<u8>>>,
+}
+
+impl Node {
+    fn update_work(&self, hash: H256, target: U256, downstream: &Downstream) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let clean_jobs = match jobs.current.as_ref().and_then(|id| jobs.by_id.get(id)) {
+            Some(job) if job.hash == hash && job.target == target => return,
+            Some(job) => job.hash != hash,
+            None => true,
+        };
+        let id = format!("{:x}", self.next_job_id.fetch_add(1, Ordering::SeqCst));
+        info!("New job {} with hash {}, target: {}", id, hash, target);
+        if clean_jobs {
+            jobs.by_id.clear();
+        }
+        jobs.by_id.insert(
+            id.clone(),
+            Job {
+                hash,
+                target,
+                seals: HashSet::new(),
+            },
+        );
+        jobs.current = Some(id.clone());
+        downstream.broadcast(&notify(&id, hash, target, clean_jobs));
+    }
+}
+
+impl Backend for Node {
+    /// A session gets the lowest extranonce1 that no other open session has.
+    fn subscribe(&self, session_id: usize) -> Result<(Vec<u8>, usize), (i64, String)> {
+        let mut extranonce1s = self.extranonce1s.lock().unwrap();
+        let extranonce1 = match extranonce1s.get(&session_id) {
+            Some(extranonce1) => *extranonce1,
+            None => (0..=u16::MAX)
+                .find(|extranonce1| !extranonce1s.values().any(|taken| taken == extranonce1))
+                .ok_or_else(|| (OTHER_CODE, "Too many miners".to_string()))?,
+        };
+        extranonce1s.insert(session_id, extranonce1);
+        Ok((extranonce1.to_be_bytes().to_vec(), EXTRANONCE2_SIZE))
+    }
+
+    fn greeting(&self) -> Vec<JsonValue> {
+        let mut messages = Vec::new();
+        if let Some(difficulty) = self.share_difficulty {
+            messages.push(json!({
+                "id": null,
+                "method": "mining.set_difficulty",
+                "params": [difficulty],
+            }));
+        }
+        let jobs = self.jobs.lock().unwrap();
+        if let Some(ref id) = jobs.current {
+            let job = &jobs.by_id[id];
+            messages.push(notify(id, job.hash, job.target, true));
+        }
+        messages
+    }
+
+    fn submit(&self, share: Share, reply: Reply) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = match jobs.by_id.get_mut(&share.job_id) {
+            Some(job) => job,
+            None => return reply.send(Err((STALE_CODE, "Job not found".to_string()))),
+        };
+        if job.hash != share.hash {
+            return reply.send(Err((OTHER_CODE, "The hash does not match the job".to_string())))
+        }
+        let score = match self.verifier.score(&share.hash, &share.seal) {
+            Some(score) => score,
+            None => return reply.send(Err((OTHER_CODE, "Invalid solution".to_string()))),
+        };
+        let share_target = self.share_target.map_or(job.target, |share_target| share_target.max(job.target));
+        if score > share_target {
+            return reply.send(Err((LOW_DIFFICULTY_CODE, "Low difficulty share".to_string())))
+        }
+        if !job.seals.insert(share.seal.clone()) {
+            return reply.send(Err((DUPLICATE_CODE, "Duplicate share".to_string())))
+        }
+        debug!("Session {:x} submitted a share for the job {} as {}", share.session_id, share.job_id, share.worker);
+        reply.send(Ok(json!(true)));
+
+        if score <= job.target {
+            info!("The worker {} found a solution for {}", share.worker, share.hash);
+            let endpoint = self.endpoint.clone();
+            let (hash, seal) = (share.hash, share.seal);
+            // Submitting blocks until the node answers, so it gets a thread of its own.
+            spawn(move || {
+                http::submit(hash, seal, &[endpoint], SubmitPolicy::Failover);
+            });
+        }
+    }
+
+    fn closed(&self, session_id: usize) {
+        self.extranonce1s.lock().unwrap().remove(&session_id);
+    }
+}
+
+fn notify(job_id: &str, hash: H256, target: U256, clean_jobs: bool) -> JsonValue {
+    json!({
+        "id": null,
+        "method": "mining.notify",
+        "params": [job_id, format!("0x{:x}", hash), format!("0x{:x}", target), clean_jobs],
+    })
+}
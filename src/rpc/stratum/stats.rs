@@ -0,0 +1,132 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+
+use ethereum_types::H256;
+use serde_json::Value as JsonValue;
+
+use super::client::AUTHORIZE_ID;
+use super::message::{Id, Response};
+
+/// The error code pools use for shares of a job that no longer exists.
+const STALE_CODE: i64 = 21;
+
+/// Counters of a stratum client, shared with the dispatchers.
+#[derive(Debug, Default)]
+pub struct Stats {
+    reconnects: AtomicUsize,
+    /// The share difficulty set by the pool, if any.
+    difficulty: RwLock<Option<f64>>,
+    next_id: AtomicUsize,
+    shares: Mutex<Shares>,
+}
+
+/// The shares submitted in the current session.
+#[derive(Debug, Default)]
+struct Shares {
+    /// Submitted shares by the id of their `mining.submit`, waiting for the response.
+    pending: HashMap<usize, H256>,
+    accepted: usize,
+    rejected: usize,
+    stale: usize,
+    /// The number of rejections by reason.
+    reasons: BTreeMap<String, usize>,
+}
+
+impl Stats {
+    /// Counts a reconnection and returns the number of reconnections so far.
+    pub fn reconnected(&self) -> usize {
+        self.reconnects.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    pub fn set_difficulty(&self, difficulty: f64) {
+        *self.difficulty.write().unwrap() = Some(difficulty);
+    }
+
+    /// Starts counting shares from zero for a new session with the pool, whose difficulty is not set yet.
+    pub fn new_session(&self) {
+        *self.difficulty.write().unwrap() = None;
+        *self.shares.lock().unwrap() = Shares::default();
+    }
+
+    /// Returns an id for a request, which no other request of the client has. The ids up to `AUTHORIZE_ID` belong to
+    /// the handshake.
+    pub fn next_id(&self) -> usize {
+        self.next_id.fetch_add(1, Ordering::SeqCst) + AUTHORIZE_ID as usize + 1
+    }
+
+    /// Records a share for `hash` and returns the id its `mining.submit` should have.
+    pub fn submitted(&self, hash: H256) -> usize {
+        let id = self.next_id();
+        self.shares.lock().unwrap().pending.insert(id, hash);
+        id
+    }
+
+    /// Counts the pool's response to a `mining.submit`. Returns false if `response` does not answer a pending share.
+    pub fn answered(&self, response: &Response) -> bool {
+        let id = match response.id.as_ref().and_then(Id::as_number) {
+            Some(id) => id as usize,
+            None => return false,
+        };
+        let mut shares = self.shares.lock().unwrap();
+        let hash = match shares.pending.remove(&id) {
+            Some(hash) => hash,
+            None => return false,
+        };
+
+        let (code, reason) = match response.result {
+            Ok(JsonValue::Bool(true)) => {
+                shares.accepted += 1;
+                info!("The pool accepted the share {} for {}", id, hash);
+                return true
+            }
+            Ok(ref result) => (None, format!("The pool answered {}", result)),
+            Err(ref error) => (error.code, error.message.clone()),
+        };
+        if code == Some(STALE_CODE) || reason.to_lowercase().contains("stale") {
+            shares.stale += 1;
+            warn!("The pool rejected the share {} for {} as stale: {}", id, hash, reason);
+        } else {
+            shares.rejected += 1;
+            warn!("The pool rejected the share {} for {}: {}", id, hash, reason);
+            *shares.reasons.entry(reason).or_insert(0) += 1;
+        }
+        true
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.difficulty.read().unwrap() {
+            Some(difficulty) => write!(f, "difficulty {}", difficulty)?,
+            None => f.write_str("difficulty of the jobs")?,
+        }
+
+        let shares = self.shares.lock().unwrap();
+        write!(f, ", {} accepted, {} rejected", shares.accepted, shares.rejected)?;
+        if !shares.reasons.is_empty() {
+            let reasons: Vec<_> =
+                shares.reasons.iter().map(|(reason, count)| format!("{}: {}", reason, count)).collect();
+            write!(f, " ({})", reasons.join(", "))?;
+        }
+        write!(f, ", {} stale, {} pending", shares.stale, shares.pending.len())?;
+        write!(f, ", {} reconnections", self.reconnects.load(Ordering::SeqCst))
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, RwLock};
+
+use rustc_hex::FromHex;
+use serde_json::Value as JsonValue;
+
+/// The nonce is a u64, and at least one byte of it is left to the worker.
+const MAX_EXTRANONCE_SIZE: usize = 7;
+
+/// What the pool assigned to this connection in response to `mining.subscribe`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Subscription {
+    /// Given back to the pool when reconnecting to resume the session.
+    pub session_id: Option<String>,
+    pub extranonce1: Vec<u8>,
+    pub extranonce2_size: usize,
+}
+
+pub type SharedSubscription = Arc<RwLock<Option<Subscription>>>;
+
+impl Subscription {
+    /// Parses the result of `mining.subscribe`, which is
+    /// `[[["mining.notify", session id], ...], extranonce1, extranonce2 size]`.
+    pub fn from_result(result: &JsonValue) -> Result<Self, String> {
+        let subscriptions = result[0].as_array().map(Vec::as_slice).unwrap_or(&[]);
+        let session_id = if subscriptions.first().map_or(false, JsonValue::is_string) {
+            // Some pools send a single ["mining.notify", session id] pair.
+            subscriptions.get(1).and_then(JsonValue::as_str)
+        } else {
+            subscriptions
+                .iter()
+                .find(|subscription| subscription[0] == "mining.notify")
+                .or_else(|| subscriptions.first())
+                .and_then(|subscription| subscription[1].as_str())
+        };
+
+        let extranonce1 = result[1]
+            .as_str()
+            .and_then(|extranonce1| extranonce1.trim_start_matches("0x").from_hex().ok())
+            .ok_or_else(|| format!("Invalid extranonce1 {}", result[1]))?;
+        let extranonce2_size = match result[2].as_u64() {
+            Some(size) if size <= MAX_EXTRANONCE_SIZE as u64 => size as usize,
+            Some(size) => return Err(format!("The extranonce2 of {} bytes does not fit in the nonce", size)),
+            None => return Err(format!("Invalid extranonce2 size {}", result[2])),
+        };
+        if extranonce1.len() + extranonce2_size > MAX_EXTRANONCE_SIZE {
+            return Err(format!(
+                "The extranonce of {} bytes does not fit in the nonce",
+                extranonce1.len() + extranonce2_size
+            ))
+        }
+
+        Ok(Self {
+            session_id: session_id.map(str::to_string),
+            extranonce1,
+            extranonce2_size,
+        })
+    }
+
+    /// Makes the extranonce2 for the `n`th job, keeping its lowest `extranonce2_size` bytes.
+    pub fn extranonce2(&self, n: usize) -> Vec<u8> {
+        let bytes = (n as u64).to_be_bytes();
+        bytes[bytes.len() - self.extranonce2_size..].to_vec()
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use futures::Future;
+use native_tls::{self, Certificate};
+use rustc_hex::{FromHex, ToHex};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio_tls::{TlsConnector, TlsStream};
+
+const END_CERTIFICATE: &str = "-----END CERTIFICATE-----";
+
+/// How to secure the connection to a `stratum+ssl://` pool.
+pub struct Tls {
+    domain: String,
+    connector: TlsConnector,
+    /// SHA-256 fingerprint of the DER-encoded certificate the pool must present.
+    pinned: Option<Vec<u8>>,
+}
+
+impl Tls {
+    /// Verifies the pool at `addr` against the system roots and the certificates in `ca_bundle`.
+    ///
+    /// If `pinned` is given as a hex SHA-256 fingerprint, the pool's certificate must match it instead, which allows
+    /// pools with self-signed certificates.
+    pub fn new(addr: &str, ca_bundle: Option<&Path>, pinned: Option<&str>) -> Result<Self, String> {
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(ca_bundle) = ca_bundle {
+            let pem = fs::read_to_string(ca_bundle).map_err(|err| format!("{}: {}", ca_bundle.display(), err))?;
+            for cert in pem.split(END_CERTIFICATE).filter(|cert| cert.contains("-----BEGIN")) {
+                let cert = Certificate::from_pem(format!("{}{}", cert, END_CERTIFICATE).as_bytes())
+                    .map_err(|err| format!("{}: {}", ca_bundle.display(), err))?;
+                builder.add_root_certificate(cert);
+            }
+        }
+        let pinned = match pinned {
+            Some(pinned) => {
+                let fingerprint: Vec<u8> = pinned
+                    .replace(':', "")
+                    .from_hex()
+                    .map_err(|_| format!("Invalid certificate fingerprint {}", pinned))?;
+                if fingerprint.len() != 32 {
+                    return Err(format!("{} is not a SHA-256 fingerprint", pinned))
+                }
+                builder.danger_accept_invalid_certs(true);
+                Some(fingerprint)
+            }
+            None => None,
+        };
+        let connector = builder.build().map_err(|err| err.to_string())?;
+
+        let host = addr.rsplitn(2, ':').last().unwrap_or(addr);
+        Ok(Self {
+            domain: host.trim_start_matches('[').trim_end_matches(']').to_string(),
+            connector: connector.into(),
+            pinned,
+        })
+    }
+
+    pub fn handshake(&self, socket: TcpStream) -> Box<Future<Item = TlsStream<TcpStream>, Error = io::Error> + Send> {
+        let pinned = self.pinned.clone();
+        Box::new(
+            self.connector
+                .connect(&self.domain, socket)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+                .and_then(move |stream| {
+                    if let Some(pinned) = pinned {
+                        let fingerprint = fingerprint(&stream)?;
+                        if fingerprint != pinned {
+                            let message = format!("the certificate {} is not the pinned one", fingerprint.to_hex());
+                            return Err(io::Error::new(io::ErrorKind::InvalidData, message))
+                        }
+                    }
+                    Ok(stream)
+                }),
+        )
+    }
+}
+
+fn fingerprint(stream: &TlsStream<TcpStream>) -> io::Result<Vec<u8>> {
+    let invalid = |err: native_tls::Error| io::Error::new(io::ErrorKind::InvalidData, err);
+    match stream.get_ref().peer_certificate().map_err(invalid)? {
+        Some(cert) => Ok(Sha256::digest(&cert.to_der().map_err(invalid)?).to_vec()),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "the pool presented no certificate")),
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::thread::spawn;
+use std::time::Duration;
+
+use futures::future::{self, Either, Loop};
+use futures::sync::oneshot;
+use futures::Future;
+use tokio::net::TcpStream;
+use tokio::timer::Timeout;
+
+const TCP_KEEPALIVE_SECS: u64 = 60;
+
+/// Resolves `addr` and tries each of its addresses in turn, giving each `timeout` to connect.
+pub fn connect(addr: &str, timeout: Duration) -> Box<Future<Item = TcpStream, Error = io::Error> + Send> {
+    let addr = addr.to_string();
+    Box::new(resolve(addr.clone()).and_then(move |addrs| {
+        future::loop_fn((addrs.into_iter(), None), move |(mut addrs, last_err): (_, Option<io::Error>)| {
+            let socket_addr = match addrs.next() {
+                Some(socket_addr) => socket_addr,
+                None => {
+                    let err = last_err
+                        .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", addr)));
+                    return Either::A(future::err(err))
+                }
+            };
+            Either::B(Timeout::new(TcpStream::connect(&socket_addr), timeout).then(move |result| match result {
+                Ok(socket) => {
+                    // Lets the system notice a peer that vanished without closing the connection.
+                    if let Err(err) = socket.set_keepalive(Some(Duration::from_secs(TCP_KEEPALIVE_SECS))) {
+                        warn!("Failed to enable TCP keepalive for {}: {}", socket_addr, err);
+                    }
+                    Ok(Loop::Break(socket))
+                }
+                Err(err) => {
+                    let err = err.into_inner().unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::TimedOut, format!("connecting to {} timed out", socket_addr))
+                    });
+                    debug!("Failed to connect to {}: {}", socket_addr, err);
+                    Ok(Loop::Continue((addrs, Some(err))))
+                }
+            }))
+        })
+    }))
+}
+
+/// The system resolver blocks, so it gets a thread of its own.
+fn resolve(addr: String) -> Box<Future<Item = Vec<SocketAddr>, Error = io::Error> + Send> {
+    let (tx, rx) = oneshot::channel();
+    let name = addr.clone();
+    spawn(move || {
+        let _ = tx.send(addr.to_socket_addrs().map(Iterator::collect));
+    });
+    Box::new(rx.then(move |result| match result {
+        Ok(addrs) => addrs,
+        Err(_) => Err(io::Error::new(io::ErrorKind::NotFound, format!("resolving {} is canceled", name))),
+    }))
+}
@@ -0,0 +1,312 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+use ethereum_types::{clean_0x, H256, U256};
+use futures::future::{self, Either, Loop};
+use futures::sync::mpsc;
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use rustc_hex::ToHex;
+use serde_json::{self, Value as JsonValue};
+use tokio::net::TcpStream;
+use tokio::timer::Delay;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{client_async, WebSocketStream};
+use url::Url;
+
+use super::super::control::Control;
+use super::super::worker::{work, Lane, Worker};
+use super::control;
+use super::http::{finish_job, start_job, SharedJob};
+use super::tcp;
+use super::RpcRunner;
+
+type Tx = mpsc::UnboundedSender<JsonValue>;
+type Rx = mpsc::UnboundedReceiver<JsonValue>;
+
+const SUBSCRIBE_ID: usize = 1;
+const CONNECT_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Clone)]
+pub struct Config {
+    /// `ws://` URL of the node.
+    pub url: String,
+    /// How long to wait before connecting again after the connection is lost.
+    pub reconnect_interval: Duration,
+    /// Serves the control API on 127.0.0.1:port if set.
+    pub control_port: Option<u16>,
+}
+
+pub struct Runner {
+    url: String,
+    reconnect_interval: Duration,
+    control_port: Option<u16>,
+}
+
+impl Runner {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            url: config.url.clone(),
+            reconnect_interval: config.reconnect_interval,
+            control_port: config.control_port,
+        }
+    }
+}
+
+impl RpcRunner for Runner {
+    fn run(&self, recruiter: Arc<Fn() -> Box<Worker> + Send + Sync>, control: Arc<Control>) {
+        let url = match Url::parse(&self.url) {
+            Ok(ref url) if url.host_str().is_none() => {
+                error!("Invalid WebSocket URL {}: no host", self.url);
+                return
+            }
+            Ok(url) => url,
+            Err(err) => {
+                error!("Invalid WebSocket URL {}: {}", self.url, err);
+                return
+            }
+        };
+        let reconnect_interval = self.reconnect_interval;
+        // Solutions found while disconnected are kept in the channel and sent after reconnecting.
+        let (tx, rx) = mpsc::unbounded();
+        let rx = Arc::new(Mutex::new(rx));
+        let lane = Arc::new(Lane::default());
+        let current_job: SharedJob = Arc::new(Mutex::new(None));
+        let submit_id = Arc::new(AtomicUsize::new(SUBSCRIBE_ID + 1));
+
+        info!("Connecting to {}", url);
+        info!("The maximum number of parallel jobs is {}", control.jobs());
+        let control_server = self.control_port.map(|port| control::serve(port, Arc::clone(&control)));
+        let client = future::loop_fn((), move |()| {
+            let reconnect = control.on_reconnect().into_future();
+            let session = Session {
+                recruiter: Arc::clone(&recruiter),
+                control: Arc::clone(&control),
+                lane: Arc::clone(&lane),
+                current_job: Arc::clone(&current_job),
+                submit_id: Arc::clone(&submit_id),
+                tx: tx.clone(),
+                rx: Arc::clone(&rx),
+            };
+            connect(&url)
+                .and_then(move |ws| {
+                    info!("Successfully connected");
+                    session.serve(ws)
+                })
+                .select2(reconnect)
+                .then(move |result| {
+                    let delay = match result {
+                        Ok(Either::A(_)) => {
+                            warn!("The node closed the connection");
+                            reconnect_interval
+                        }
+                        Err(Either::A((err, _))) => {
+                            error!("WebSocket error: {}", err);
+                            reconnect_interval
+                        }
+                        Ok(Either::B(_)) | Err(Either::B(_)) => Duration::from_secs(0),
+                    };
+                    info!("Reconnecting in {:?}", delay);
+                    Delay::new(Instant::now() + delay).then(|_| Ok(Loop::Continue(())))
+                })
+        });
+
+        ::tokio::run(future::lazy(move || {
+            if let Some(control_server) = control_server {
+                ::tokio::spawn(control_server);
+            }
+            client
+        }));
+    }
+}
+
+fn connect(url: &Url) -> Box<Future<Item = WebSocketStream<TcpStream>, Error = WsError> + Send> {
+    let url = url.clone();
+    let addr = match (url.host_str(), url.port_or_known_default()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        _ => return Box::new(future::err(io::Error::new(io::ErrorKind::NotFound, "no address").into())),
+    };
+    Box::new(
+        tcp::connect(&addr, Duration::from_secs(CONNECT_TIMEOUT_SECS))
+            .map_err(WsError::from)
+            .and_then(move |socket| client_async(url, socket).map(|(ws, _response)| ws)),
+    )
+}
+
+struct Session {
+    recruiter: Arc<Fn() -> Box<Worker> + Send + Sync>,
+    control: Arc<Control>,
+    lane: Arc<Lane>,
+    current_job: SharedJob,
+    submit_id: Arc<AtomicUsize>,
+    tx: Tx,
+    rx: Arc<Mutex<Rx>>,
+}
+
+impl Session {
+    fn serve(self, mut ws: WebSocketStream<TcpStream>) -> Box<Future<Item = (), Error = WsError> + Send> {
+        let subscribe = json!({
+            "jsonrpc": "2.0",
+            "id": SUBSCRIBE_ID,
+            "method": "miner_subscribe",
+            "params": ["newWork"],
+        });
+        debug!("Send a subscription message");
+        let unsent = match ws.start_send(Message::Text(subscribe.to_string())) {
+            Ok(AsyncSink::Ready) => None,
+            Ok(AsyncSink::NotReady(_)) => Some(subscribe),
+            Err(err) => return Box::new(future::err(err)),
+        };
+        if let Err(err) = ws.poll_complete() {
+            return Box::new(future::err(err))
+        }
+
+        Box::new(Connection {
+            ws,
+            session: self,
+            unsent,
+        })
+    }
+
+    fn handle(&self, text: &str) {
+        let message: JsonValue = match serde_json::from_str(text) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!("Invalid message from the node: {}", err);
+                return
+            }
+        };
+
+        if message["method"] == "miner_subscription" {
+            self.notified(&message["params"]["result"]);
+        } else if message["id"] == SUBSCRIBE_ID {
+            if message["error"].is_null() {
+                info!("Subscribed to new work ({})", message["result"]);
+            } else {
+                warn!("Failed to subscribe to new work: {}", message["error"]);
+            }
+        } else if message["result"] == true {
+            info!("The node accepted the solution {}", message["id"]);
+        } else if !message["error"].is_null() {
+            warn!("The node rejected the solution {}: {}", message["id"], message["error"]);
+        } else {
+            debug!("Unhandled message: {}", message);
+        }
+    }
+
+    fn notified(&self, params: &JsonValue) {
+        let (hash, target) = match (params[0].as_str(), params[1].as_str()) {
+            (Some(hash), Some(target)) => (hash, target),
+            _ => {
+                warn!("Invalid work notification: {}", params);
+                return
+            }
+        };
+        let (hash, target) = match (H256::from_str(clean_0x(hash)), U256::from_str(clean_0x(target))) {
+            (Ok(hash), Ok(target)) => (hash, target),
+            _ => {
+                warn!("Invalid work notification: {}", params);
+                return
+            }
+        };
+
+        if let Some(target) = start_job(&self.current_job, hash, target) {
+            let recruiter = Arc::clone(&self.recruiter);
+            let control = Arc::clone(&self.control);
+            let lane = Arc::clone(&self.lane);
+            let id = self.submit_id.fetch_add(1, Ordering::SeqCst);
+            let tx = self.tx.clone();
+            let current_job = Arc::clone(&self.current_job);
+            spawn(move || {
+                let solution = work(&hash, &target, &[], &recruiter, &control, &lane);
+                finish_job(&current_job, &target);
+                if let Some(solution) = solution {
+                    let _ = tx.unbounded_send(submit(id, hash, solution));
+                }
+            });
+        }
+    }
+}
+
+struct Connection {
+    ws: WebSocketStream<TcpStream>,
+    session: Session,
+    /// A message taken from `rx` that the socket could not take yet.
+    unsent: Option<JsonValue>,
+}
+
+impl Future for Connection {
+    type Item = ();
+    type Error = WsError;
+
+    fn poll(&mut self) -> Poll<(), WsError> {
+        loop {
+            let json_value = match self.unsent.take() {
+                Some(json_value) => json_value,
+                None => match self.session.rx.lock().unwrap().poll().expect("Receiving from a channel never fails") {
+                    Async::Ready(Some(json_value)) => json_value,
+                    Async::Ready(None) | Async::NotReady => break,
+                },
+            };
+            debug!("Send a message: {:?}", json_value);
+            if let AsyncSink::NotReady(_) = self.ws.start_send(Message::Text(json_value.to_string()))? {
+                self.unsent = Some(json_value);
+                break
+            }
+        }
+        self.ws.poll_complete()?;
+
+        loop {
+            match try_ready!(self.ws.poll()) {
+                Some(Message::Text(text)) => self.session.handle(&text),
+                Some(Message::Binary(_)) => warn!("Unexpected binary message"),
+                Some(Message::Ping(_)) | Some(Message::Pong(_)) => {}
+                Some(Message::Close(_)) | None => return Ok(Async::Ready(())),
+            }
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // The message is sent on the next connection instead, which subscribes on its own.
+        if let Some(json_value) = self.unsent.take() {
+            if json_value["id"] != SUBSCRIBE_ID {
+                let _ = self.session.tx.unbounded_send(json_value);
+            }
+        }
+    }
+}
+
+pub fn submit(id: usize, hash: H256, solution: Vec<Vec<u8>>) -> JsonValue {
+    let seal: Vec<_> = solution.iter().map(|bytes| format!("0x{}", bytes.to_hex())).collect();
+    info!("Job finished with hash {}, seal {:?}", hash, seal);
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "method": "miner_submitWork",
+        "params": [
+            format!("0x{:x}", hash),
+            seal,
+        ],
+    })
+}
@@ -0,0 +1,21 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+pub use rpc::stratum::{
+    dispatch_fn, Client, ClientBuilder as Builder, ClientPool as Pool, Dispatch, Error, Event, Handle, Id,
+    Kind as ErrorKind, Layer, Layered, Log, LogLayer, Measured, Message, Method, Metrics, MetricsLayer, NewDispatch,
+    Notify, Response, Result, RpcError, Strictness, Submit, Timeout, TimeoutLayer, Tls,
+};
@@ -16,9 +16,32 @@
 
 mod work;
 
+use std::str::FromStr;
+
 use ethereum_types::U256;
 
-pub use self::work::work;
+pub use self::work::{work, Lane};
+
+/// Where each job starts searching.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NonceStrategy {
+    /// Start from nonce 0.
+    Sequential,
+    /// Start from a random nonce, so that miners sharing a work rarely search the same nonces.
+    Random,
+}
+
+impl FromStr for NonceStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sequential" => Ok(NonceStrategy::Sequential),
+            "random" => Ok(NonceStrategy::Random),
+            _ => Err(format!("Unknown nonce strategy {}", s)),
+        }
+    }
+}
 
 pub trait Worker: Send {
     fn init(&mut self, message: &[u8], nonce: u64, target: &U256);
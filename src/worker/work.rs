@@ -14,33 +14,164 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, RwLock};
+use std::thread::spawn;
 
 use ethereum_types::{H256, U256};
+use rand;
 
-use super::Worker;
+use super::super::control::Control;
+use super::{NonceStrategy, Worker};
 
 static JOB_ID: AtomicUsize = AtomicUsize::new(0);
 
-pub fn work(hash: &H256, target: &U256, mut worker: Box<Worker>, jobs: usize) -> Option<Vec<Vec<u8>>> {
+/// The jobs that replace one another, such as the jobs of one pool connection.
+///
+/// A job is stopped once more than `Control::jobs` newer jobs of its lane have started, or, in a lane made by
+/// `Lane::new`, only once `Lane::clean` is called. Jobs of the other lanes are not counted.
+#[derive(Debug, Default)]
+pub struct Lane {
+    started: AtomicUsize,
+    /// The jobs started before this are stopped.
+    cleaned: AtomicUsize,
+    threads: Option<usize>,
+    share: usize,
+    /// Whether the jobs run until `clean` stops them, however many newer jobs start.
+    until_cleaned: bool,
+}
+
+impl Lane {
+    /// Creates a lane whose jobs use `threads` threads, or `Control::threads` split between `share` lanes, and run
+    /// until `clean` stops them.
+    pub fn new(threads: Option<usize>, share: usize) -> Self {
+        Self {
+            started: AtomicUsize::new(0),
+            cleaned: AtomicUsize::new(0),
+            threads,
+            share,
+            until_cleaned: true,
+        }
+    }
+
+    /// Stops the jobs started so far.
+    pub fn clean(&self) {
+        self.cleaned.store(self.started.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+
+    fn threads(&self, control: &Control) -> usize {
+        self.threads.unwrap_or_else(|| control.threads() / self.share.max(1)).max(1)
+    }
+
+    /// Whether the job that started `sequence`th has to stop.
+    fn stops(&self, sequence: usize, control: &Control) -> bool {
+        if self.cleaned.load(Ordering::SeqCst) > sequence {
+            return true
+        }
+        !self.until_cleaned && self.started.load(Ordering::SeqCst) > sequence + control.jobs()
+    }
+}
+
+/// Searches a solution for `hash`.
+///
+/// The nonces are split between the threads of `lane`, each with its own worker.
+/// `target` is read again before every nonce, so it can be changed while the job is running.
+/// `extranonce` fills the most significant bytes of every nonce, and only the rest is searched.
+pub fn work(
+    hash: &H256,
+    target: &Arc<RwLock<U256>>,
+    extranonce: &[u8],
+    recruiter: &Arc<Fn() -> Box<Worker> + Send + Sync>,
+    control: &Arc<Control>,
+    lane: &Arc<Lane>,
+) -> Option<Vec<Vec<u8>>> {
+    assert!(extranonce.len() < 8, "The extranonce should leave room for the nonce");
     let id = JOB_ID.fetch_add(1, Ordering::SeqCst);
-    info!("Starting a new Job {} with hash {}, target: {}", id, hash, target);
-    for nonce in 0..=u64::max_value() {
-        worker.init(hash, nonce, target);
-        while !worker.is_finished() {
-            if JOB_ID.load(Ordering::SeqCst) > id + jobs {
-                info!("A new job submitted. Stopping the job {}", id);
-                return None
+    let sequence = lane.started.fetch_add(1, Ordering::SeqCst);
+    let threads = lane.threads(control);
+    let start: u64 = match control.nonce_strategy() {
+        NonceStrategy::Sequential => 0,
+        NonceStrategy::Random => rand::random(),
+    };
+    info!("Starting a new Job {} with hash {}, target: {}, threads: {}", id, hash, *target.read().unwrap(), threads);
+
+    let free_bits = 64 - 8 * extranonce.len() as u32;
+    let prefix = extranonce.iter().fold(0u64, |prefix, byte| prefix << 8 | u64::from(*byte));
+    let job = Arc::new(Job {
+        id,
+        hash: *hash,
+        prefix: prefix.checked_shl(free_bits).unwrap_or(0),
+        mask: u64::max_value().checked_shr(64 - free_bits).unwrap_or(0),
+        target: Arc::clone(target),
+        control: Arc::clone(control),
+        lane: Arc::clone(lane),
+        sequence,
+        finished: AtomicBool::new(false),
+    });
+    let (tx, rx) = channel();
+    for index in 0..threads {
+        let job = Arc::clone(&job);
+        let worker = recruiter();
+        let tx = tx.clone();
+        spawn(move || {
+            let _ = tx.send(job.search(worker, start.wrapping_add(index as u64), threads as u64));
+        });
+    }
+    drop(tx);
+
+    let solution = rx.iter().find(Option::is_some).and_then(|solution| solution);
+    job.finished.store(true, Ordering::SeqCst);
+    solution
+}
+
+struct Job {
+    id: usize,
+    hash: H256,
+    /// The extranonce in place.
+    prefix: u64,
+    /// The bits of the nonce left to search.
+    mask: u64,
+    target: Arc<RwLock<U256>>,
+    control: Arc<Control>,
+    lane: Arc<Lane>,
+    /// The order in which the job started in its lane.
+    sequence: usize,
+    /// Set when a thread of this job found a solution.
+    finished: AtomicBool,
+}
+
+impl Job {
+    /// Tries the nonces `start`, `start + step`, `start + 2 * step`, ... below the extranonce.
+    fn search(&self, mut worker: Box<Worker>, start: u64, step: u64) -> Option<Vec<Vec<u8>>> {
+        let mut current_target = *self.target.read().unwrap();
+        for count in 0..=(self.mask / step) {
+            let nonce = self.prefix | (start.wrapping_add(count * step) & self.mask);
+            let new_target = *self.target.read().unwrap();
+            if new_target != current_target {
+                info!("The target of the job {} is changed to {} at nonce {}", self.id, new_target, nonce);
+                current_target = new_target;
             }
-            match worker.proceed() {
-                Some(solution) => {
-                    info!("Nonce: {}", nonce);
-                    return Some(solution)
+            worker.init(&self.hash, nonce, &current_target);
+            while !worker.is_finished() {
+                self.control.wait_while_paused();
+                if self.finished.load(Ordering::SeqCst) {
+                    return None
+                }
+                if self.lane.stops(self.sequence, &self.control) {
+                    info!("A new job submitted. Stopping the job {}", self.id);
+                    return None
+                }
+                match worker.proceed() {
+                    Some(solution) => {
+                        info!("Nonce: {}", nonce);
+                        return Some(solution)
+                    }
+                    None => {}
                 }
-                None => {}
             }
         }
+        info!("Could not find the solution for hash {}", self.hash);
+        None
     }
-    info!("Could not find the solution for hash {}", hash);
-    None
 }
@@ -0,0 +1,147 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Fixtures shared by the tests. Not every test uses all of them.
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use codechain_miner::{Config, RpcConfig, StratumPool, Worker};
+use ethereum_types::U256;
+use serde_json::{self, Value as JsonValue};
+
+/// Finds a solution at the first nonce it is given, and seals it with the nonce.
+pub struct InstantWorker {
+    nonce: u64,
+}
+
+impl InstantWorker {
+    pub fn boxed() -> Box<Worker> {
+        Box::new(InstantWorker {
+            nonce: 0,
+        })
+    }
+}
+
+impl Worker for InstantWorker {
+    fn init(&mut self, _message: &[u8], nonce: u64, _target: &U256) {
+        self.nonce = nonce;
+    }
+
+    fn proceed(&mut self) -> Option<Vec<Vec<u8>>> {
+        Some(vec![self.nonce.to_be_bytes().to_vec()])
+    }
+
+    fn is_finished(&self) -> bool {
+        false
+    }
+}
+
+/// Runs `rpc_config` with a single job of the workers `worker` makes.
+pub struct TestConfig {
+    pub rpc_config: RpcConfig,
+    pub worker: fn() -> Box<Worker>,
+}
+
+impl TestConfig {
+    pub fn new(rpc_config: RpcConfig) -> Self {
+        Self {
+            rpc_config,
+            worker: InstantWorker::boxed,
+        }
+    }
+}
+
+impl Config for TestConfig {
+    fn rpc_config(&self) -> RpcConfig {
+        self.rpc_config.clone()
+    }
+
+    fn jobs(&self) -> usize {
+        1
+    }
+
+    fn worker(&self) -> Box<Worker> {
+        (self.worker)()
+    }
+}
+
+/// A pool at `url` where the miner works as "miner" with the password "secret".
+pub fn pool(url: String) -> StratumPool {
+    StratumPool {
+        url,
+        id: "miner".to_string(),
+        pwd: "secret".to_string(),
+        ca_bundle: None,
+        pinned_cert: None,
+    }
+}
+
+pub fn read_json<S: Read>(stream: &mut BufReader<S>) -> JsonValue {
+    let mut line = String::new();
+    stream.read_line(&mut line).unwrap();
+    serde_json::from_str(&line).unwrap()
+}
+
+pub fn write_json<S: Write>(stream: &mut BufReader<S>, value: JsonValue) {
+    let stream = stream.get_mut();
+    stream.write_all(value.to_string().as_bytes()).unwrap();
+    stream.write_all(b"\n").unwrap();
+    stream.flush().unwrap();
+}
+
+/// Answers the subscription of a miner with `subscribed`, or with an error if it is null, and authorizes the miner.
+/// Returns the parameters of the authorization.
+pub fn handshake<S: Read + Write>(stream: &mut BufReader<S>, subscribed: JsonValue) -> JsonValue {
+    let subscribe = read_json(stream);
+    assert_eq!(subscribe["method"], "mining.subscribe");
+    let error = if subscribed.is_null() {
+        json!([20, "Not supported", null])
+    } else {
+        JsonValue::Null
+    };
+    write_json(
+        stream,
+        json!({
+            "id": subscribe["id"],
+            "result": subscribed,
+            "error": error,
+        }),
+    );
+
+    let authorize = read_json(stream);
+    assert_eq!(authorize["method"], "mining.authorize");
+    write_json(
+        stream,
+        json!({
+            "id": authorize["id"],
+            "result": true,
+            "error": null,
+        }),
+    );
+    authorize["params"].clone()
+}
+
+/// Accepts a miner on `listener` as a pool that does not support extranonces.
+pub fn accept(listener: &TcpListener) -> BufReader<TcpStream> {
+    let (socket, _) = listener.accept().unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut stream = BufReader::new(socket);
+    handshake(&mut stream, JsonValue::Null);
+    stream
+}
@@ -0,0 +1,156 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+extern crate codechain_miner;
+extern crate ethereum_types;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use codechain_miner::{RpcConfig, StratumConfig, Worker};
+use ethereum_types::U256;
+use serde_json::Value as JsonValue;
+
+use common::{accept, pool, read_json, write_json, TestConfig};
+
+const STUCK_HASH: &str = "0x0000000000000000000000000000000000000000000000000000000000000001";
+const EASY_HASH: &str = "0x0000000000000000000000000000000000000000000000000000000000000002";
+
+/// The miners of the tests share `STUCK_PROCEEDS`, so a stuck job of one test would be counted by another.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// The number of nonces tried for `STUCK_HASH`.
+static STUCK_PROCEEDS: AtomicUsize = AtomicUsize::new(0);
+
+/// Never finds a solution for `STUCK_HASH`, and finds one at the first nonce for any other hash.
+struct SelectiveWorker {
+    stuck: bool,
+    nonce: u64,
+}
+
+impl Worker for SelectiveWorker {
+    fn init(&mut self, message: &[u8], nonce: u64, _target: &U256) {
+        self.stuck = message[31] == 1;
+        self.nonce = nonce;
+    }
+
+    fn proceed(&mut self) -> Option<Vec<Vec<u8>>> {
+        if self.stuck {
+            STUCK_PROCEEDS.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(1));
+            None
+        } else {
+            Some(vec![vec![self.nonce as u8]])
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        false
+    }
+}
+
+fn notify(stream: &mut BufReader<TcpStream>, hash: &str) {
+    notify_job(stream, json!([hash, "0xffff"]));
+}
+
+fn notify_job(stream: &mut BufReader<TcpStream>, params: JsonValue) {
+    write_json(
+        stream,
+        json!({
+            "id": null,
+            "method": "mining.notify",
+            "params": params,
+        }),
+    );
+}
+
+/// Waits until a job of `STUCK_HASH` tries another nonce.
+fn wait_stuck_job() {
+    let proceeds = STUCK_PROCEEDS.load(Ordering::SeqCst);
+    while STUCK_PROCEEDS.load(Ordering::SeqCst) == proceeds {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Starts a miner and accepts its connection as a pool that does not support extranonces.
+fn start_miner() -> BufReader<TcpStream> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("stratum+tcp://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        codechain_miner::run(TestConfig {
+            rpc_config: RpcConfig::Stratum(StratumConfig::new(vec![pool(url)])),
+            worker: || {
+                Box::new(SelectiveWorker {
+                    stuck: false,
+                    nonce: 0,
+                })
+            },
+        })
+    });
+    accept(&listener)
+}
+
+#[test]
+fn stratum_notify_preempts_the_running_job() {
+    let _serial = SERIAL.lock().unwrap();
+    let mut stream = start_miner();
+
+    notify(&mut stream, STUCK_HASH);
+    wait_stuck_job();
+
+    // The client keeps reading while the first job runs, and the new job replaces it.
+    notify(&mut stream, EASY_HASH);
+    let submit = read_json(&mut stream);
+    assert_eq!(submit["method"], "mining.submit");
+    assert_eq!(submit["params"], json!([EASY_HASH, ["0x00"]]));
+
+    let proceeds = STUCK_PROCEEDS.load(Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(STUCK_PROCEEDS.load(Ordering::SeqCst), proceeds, "The first job should have stopped");
+}
+
+#[test]
+fn stratum_follows_clean_jobs_and_submits_the_job_id() {
+    let _serial = SERIAL.lock().unwrap();
+    let mut stream = start_miner();
+
+    notify_job(&mut stream, json!(["job1", STUCK_HASH, "0xffff", true]));
+    wait_stuck_job();
+
+    // A job that does not clean the previous ones runs along with them.
+    notify_job(&mut stream, json!(["job2", EASY_HASH, "0xffff", false, "extra"]));
+    let submit = read_json(&mut stream);
+    assert_eq!(submit["method"], "mining.submit");
+    assert_eq!(submit["params"], json!(["miner", "job2", EASY_HASH, ["0x00"]]));
+    wait_stuck_job();
+
+    notify_job(&mut stream, json!(["job3", EASY_HASH, "0xffff", true]));
+    let submit = read_json(&mut stream);
+    assert_eq!(submit["method"], "mining.submit");
+    assert_eq!(submit["params"], json!(["miner", "job3", EASY_HASH, ["0x00"]]));
+
+    let proceeds = STUCK_PROCEEDS.load(Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(STUCK_PROCEEDS.load(Ordering::SeqCst), proceeds, "The cleaned job should have stopped");
+}
@@ -0,0 +1,219 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+extern crate codechain_miner;
+extern crate ethereum_types;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use codechain_miner::{Endpoint, RpcConfig, StratumConfig, StratumServerConfig, Strictness, Verifier};
+use ethereum_types::{H256, U256};
+use serde_json::Value as JsonValue;
+
+use common::{pool, read_json, TestConfig};
+
+const HASH: &str = "0x0000000000000000000000000000000000000000000000000000000000000001";
+const TARGET: &str = "0xffff";
+
+/// Takes every seal of a single nonce as a solution of any block.
+struct NonceVerifier;
+
+impl Verifier for NonceVerifier {
+    fn score(&self, _hash: &H256, seal: &[Vec<u8>]) -> Option<U256> {
+        if seal.len() == 1 && seal[0].len() == 8 {
+            Some(U256::zero())
+        } else {
+            None
+        }
+    }
+}
+
+/// Serves `miner_getWork` with `HASH` and sends the parameters of every `miner_submitWork` to `submissions`.
+fn run_node(submissions: Sender<JsonValue>) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for socket in listener.incoming() {
+            let submissions = submissions.clone();
+            thread::spawn(move || serve_http(socket.unwrap(), &submissions));
+        }
+    });
+    port
+}
+
+fn serve_http(socket: TcpStream, submissions: &Sender<JsonValue>) {
+    let mut stream = BufReader::new(socket);
+    loop {
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).unwrap_or(0) == 0 {
+                return
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break
+            }
+            let mut header = line.splitn(2, ':');
+            if header.next().unwrap().eq_ignore_ascii_case("content-length") {
+                content_length = header.next().unwrap().trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; content_length];
+        stream.read_exact(&mut body).unwrap();
+        let request: JsonValue = serde_json::from_slice(&body).unwrap();
+
+        let result = match request["method"].as_str().unwrap() {
+            "miner_getWork" => json!([HASH, TARGET]),
+            "miner_submitWork" => {
+                let _ = submissions.send(request["params"].clone());
+                json!(true)
+            }
+            method => panic!("Unexpected method {}", method),
+        };
+        let response = json!({"jsonrpc": "2.0", "id": request["id"], "result": result}).to_string();
+        let stream = stream.get_mut();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n",
+            response.len()
+        )
+        .unwrap();
+        stream.write_all(response.as_bytes()).unwrap();
+    }
+}
+
+/// Starts a server for a node that sends the parameters of submitted solutions to `submissions`.
+fn run_server(submissions: Sender<JsonValue>) -> SocketAddr {
+    let node_port = run_node(submissions);
+    let listen = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut credentials = HashMap::new();
+    credentials.insert("miner".to_string(), "secret".to_string());
+    let config = StratumServerConfig {
+        listen,
+        node: Endpoint::Port(node_port),
+        poll_interval: Duration::from_millis(100),
+        share_difficulty: Some(2.0),
+        credentials: Arc::new(credentials),
+        verifier: Arc::new(NonceVerifier),
+        max_frame_size: 64 * 1024,
+        strictness: Strictness::Strict,
+    };
+    thread::spawn(move || codechain_miner::run(TestConfig::new(RpcConfig::StratumServer(config))));
+    listen
+}
+
+fn connect(addr: SocketAddr) -> BufReader<TcpStream> {
+    for _ in 0..100 {
+        if let Ok(socket) = TcpStream::connect(addr) {
+            socket.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            return BufReader::new(socket)
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("The server did not start");
+}
+
+/// Sends a request and returns its response, skipping the notifications before it.
+fn call(stream: &mut BufReader<TcpStream>, id: u64, method: &str, params: JsonValue) -> JsonValue {
+    let request = json!({"id": id, "method": method, "params": params});
+    writeln!(stream.get_mut(), "{}", request).unwrap();
+    loop {
+        let message = read_json(stream);
+        if message["id"] == id {
+            return message
+        }
+    }
+}
+
+#[test]
+fn stratum_server_forwards_solutions_of_its_miners() {
+    let (submissions, submitted) = channel();
+    let addr = run_server(submissions);
+    // The miner keeps retrying until the server is up.
+    let pools = vec![pool(format!("stratum+tcp://{}", addr))];
+    thread::spawn(move || codechain_miner::run(TestConfig::new(RpcConfig::Stratum(StratumConfig::new(pools)))));
+
+    let params = submitted.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(params[0], HASH);
+    // The nonce starts with the extranonce1 of the first session and the extranonce2 of the first job.
+    assert_eq!(params[1], json!(["0x0000000100000000"]));
+}
+
+#[test]
+fn stratum_server_checks_credentials_and_shares() {
+    let (submissions, _submitted) = channel();
+    let addr = run_server(submissions);
+    let mut stream = connect(addr);
+
+    let subscribed = call(&mut stream, 1, "mining.subscribe", json!(["test"]));
+    assert_eq!(subscribed["result"][1], "0000");
+    assert_eq!(subscribed["result"][2], 2);
+
+    let refused = call(&mut stream, 2, "mining.authorize", json!(["miner", "wrong"]));
+    assert_eq!(refused["error"][0], 24);
+    let submit = |job_id: &str, seal: &str| json!(["miner", job_id, HASH, [seal], "0000"]);
+    let share = "0x0000000000000000";
+    assert_eq!(call(&mut stream, 3, "mining.submit", submit("1", share))["error"][0], 24);
+
+    assert_eq!(call(&mut stream, 4, "mining.authorize", json!(["miner", "secret"]))["result"], true);
+    let job_id = loop {
+        let notification = read_json(&mut stream);
+        if notification["method"] == "mining.notify" {
+            break notification["params"][0].as_str().unwrap().to_string()
+        }
+    };
+    assert_eq!(call(&mut stream, 5, "mining.submit", submit(&job_id, "0x00"))["error"][0], 20);
+    assert_eq!(call(&mut stream, 6, "mining.submit", submit("stale", share))["error"][0], 21);
+    assert_eq!(call(&mut stream, 7, "mining.submit", submit(&job_id, share))["result"], true);
+    assert_eq!(call(&mut stream, 8, "mining.submit", submit(&job_id, share))["error"][0], 22);
+}
+
+#[test]
+fn stratum_server_reuses_the_extranonce1_of_closed_sessions() {
+    let (submissions, _submitted) = channel();
+    let addr = run_server(submissions);
+    let subscribe = || {
+        let mut stream = connect(addr);
+        let extranonce1 = call(&mut stream, 1, "mining.subscribe", json!(["test"]))["result"][1].clone();
+        (stream, extranonce1)
+    };
+
+    let (first, extranonce1) = subscribe();
+    assert_eq!(extranonce1, "0000");
+    let (_second, extranonce1) = subscribe();
+    assert_eq!(extranonce1, "0001");
+
+    drop(first);
+    // The server frees the extranonce1 once it notices the closed connection.
+    for _ in 0..100 {
+        if subscribe().1 == "0000" {
+            return
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("The extranonce1 of the closed session was not reused");
+}
@@ -0,0 +1,112 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+extern crate codechain_miner;
+extern crate ethereum_types;
+extern crate native_tls;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::io::{BufRead, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use codechain_miner::{RpcConfig, StratumConfig};
+use native_tls::{Identity, TlsAcceptor, TlsStream};
+
+use common::{handshake, pool, read_json, write_json, TestConfig};
+
+const HASH: &str = "0x0000000000000000000000000000000000000000000000000000000000000001";
+const CERT: &[u8] = include_bytes!("fixtures/pool.crt");
+const KEY: &[u8] = include_bytes!("fixtures/pool.key");
+const FINGERPRINT: &str =
+    "69:93:8B:AD:9D:91:D4:41:81:B9:0D:A9:90:D6:3B:B6:4B:92:83:78:5E:CE:36:91:83:C4:8A:64:9B:2E:AE:92";
+
+/// Starts a miner connecting to a pool on a new port and returns the pool's listener.
+fn start_miner(host: &str, ca_bundle: Option<PathBuf>, pinned_cert: Option<&str>) -> TcpListener {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("stratum+ssl://{}:{}", host, listener.local_addr().unwrap().port());
+    let mut pool = pool(url);
+    pool.ca_bundle = ca_bundle;
+    pool.pinned_cert = pinned_cert.map(str::to_string);
+    thread::spawn(move || codechain_miner::run(TestConfig::new(RpcConfig::Stratum(StratumConfig::new(vec![pool])))));
+    listener
+}
+
+fn accept(listener: &TcpListener) -> Result<BufReader<TlsStream<TcpStream>>, native_tls::HandshakeError<TcpStream>> {
+    let acceptor = TlsAcceptor::new(Identity::from_pkcs8(CERT, KEY).unwrap()).unwrap();
+    let (socket, _) = listener.accept().unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    acceptor.accept(socket).map(BufReader::new)
+}
+
+/// Subscribes and authorizes the miner, gives it work and checks the solution it submits.
+fn mine(stream: &mut BufReader<TlsStream<TcpStream>>) {
+    let authorization = handshake(stream, json!([[["mining.notify", "ae6812eb4cd7735a302a8a9dd95cf71f"]], "abcd", 2]));
+    assert_eq!(authorization, json!(["miner", "secret"]));
+    // The notification is sent separately so that the client reads it after the authorization.
+    thread::sleep(Duration::from_millis(100));
+    write_json(
+        stream,
+        json!({
+            "id": 2,
+            "method": "mining.notify",
+            "params": [HASH, "0xffff"],
+        }),
+    );
+
+    let submit = read_json(stream);
+    assert_eq!(submit["method"], "mining.submit");
+    assert_eq!(submit["params"][0], HASH);
+    // The nonce starts with extranonce1 and the extranonce2 of the first job.
+    assert_eq!(submit["params"][1], json!(["0xabcd000100000000"]));
+    assert_eq!(submit["params"][2], "0001");
+}
+
+#[test]
+fn stratum_over_tls_trusts_the_ca_bundle() {
+    let ca_bundle = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/pool.crt"));
+    let listener = start_miner("localhost", Some(ca_bundle), None);
+    let mut stream = accept(&listener).unwrap();
+    mine(&mut stream);
+}
+
+#[test]
+fn stratum_over_tls_rejects_an_untrusted_certificate() {
+    let listener = start_miner("localhost", None, None);
+    assert!(accept(&listener).is_err());
+}
+
+#[test]
+fn stratum_over_tls_accepts_the_pinned_certificate() {
+    let listener = start_miner("127.0.0.1", None, Some(FINGERPRINT));
+    let mut stream = accept(&listener).unwrap();
+    mine(&mut stream);
+}
+
+#[test]
+fn stratum_over_tls_rejects_a_certificate_not_pinned() {
+    let other = "00".repeat(32);
+    let listener = start_miner("127.0.0.1", None, Some(&other));
+    // The handshake itself succeeds, but the miner hangs up before authorizing.
+    let mut stream = accept(&listener).unwrap();
+    let mut line = String::new();
+    assert_eq!(stream.read_line(&mut line).unwrap_or(0), 0, "The miner should hang up: {}", line);
+}
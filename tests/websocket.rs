@@ -0,0 +1,112 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+extern crate codechain_miner;
+extern crate ethereum_types;
+#[macro_use]
+extern crate serde_json;
+extern crate tokio_tungstenite;
+
+mod common;
+
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use codechain_miner::{RpcConfig, WebSocketConfig};
+use serde_json::Value as JsonValue;
+use tokio_tungstenite::tungstenite::{server, Message, WebSocket};
+
+use common::TestConfig;
+
+const HASH: &str = "0x0000000000000000000000000000000000000000000000000000000000000001";
+
+fn read_json(ws: &mut WebSocket<TcpStream>) -> JsonValue {
+    loop {
+        match ws.read_message().unwrap() {
+            Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+            _ => continue,
+        }
+    }
+}
+
+fn write_json(ws: &mut WebSocket<TcpStream>, value: JsonValue) {
+    ws.write_message(Message::Text(value.to_string())).unwrap();
+}
+
+fn accept_subscription(listener: &TcpListener) -> WebSocket<TcpStream> {
+    let (socket, _) = listener.accept().unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut ws = server::accept(socket).unwrap();
+
+    let subscribe = read_json(&mut ws);
+    assert_eq!(subscribe["method"], "miner_subscribe");
+    assert_eq!(subscribe["params"], json!(["newWork"]));
+    write_json(
+        &mut ws,
+        json!({
+            "jsonrpc": "2.0",
+            "id": subscribe["id"],
+            "result": "0x1",
+        }),
+    );
+    ws
+}
+
+#[test]
+fn websocket_subscribes_submits_and_resubscribes() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let config = WebSocketConfig {
+        url,
+        reconnect_interval: Duration::from_millis(100),
+        control_port: None,
+    };
+    thread::spawn(move || codechain_miner::run(TestConfig::new(RpcConfig::WebSocket(config))));
+
+    let mut ws = accept_subscription(&listener);
+    write_json(
+        &mut ws,
+        json!({
+            "jsonrpc": "2.0",
+            "method": "miner_subscription",
+            "params": {
+                "subscription": "0x1",
+                "result": [HASH, "0xffff"],
+            },
+        }),
+    );
+
+    let submit = read_json(&mut ws);
+    assert_eq!(submit["method"], "miner_submitWork");
+    assert_eq!(submit["params"][0], HASH);
+    assert_eq!(submit["params"][1], json!(["0x0000000000000000"]));
+    write_json(
+        &mut ws,
+        json!({
+            "jsonrpc": "2.0",
+            "id": submit["id"],
+            "result": true,
+        }),
+    );
+
+    ws.close(None).unwrap();
+    let _ = ws.write_pending();
+    drop(ws);
+
+    // The miner must connect and subscribe again by itself.
+    accept_subscription(&listener);
+}